@@ -29,6 +29,7 @@ fn main() {
 					radius: 0.15,
 				}),
 				emission: Vec3::thrice(1.0) * 20.0,
+				profile: None,
 			}),
 			*/
 			Object::Emitter(AreaLight { // light
@@ -38,6 +39,7 @@ fn main() {
 					0.5
 				)),
 				emission: Vec3::new(1.0, 0.772549, 0.560784) * 40.0,
+				profile: None,
 			}),
 			/*
 			Object::Emitter(AreaLight { // light
@@ -47,12 +49,14 @@ fn main() {
 					0.25
 				)),
 				emission: Vec3::thrice(1.0) * 20.0,
+				profile: None,
 			}),
 			*/
 			/*
 			Object::Emitter(AreaLight { // light
 				surface: Box::new(Sphere::new(0.10, Vec3 { x: -3.0, y: 3.0, z: -3.0 })),
 				emission: Vec3::new(1.0, 0.772549, 0.560784) * 4000.0,
+				profile: None,
 			}),
 			*/
 			Object::Scatterer {
@@ -9,7 +9,7 @@ use std::io::BufReader;
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use tracing::{math, scene, camera, material, texture, primitive, obj, light};
+use tracing::{math, scene, camera, material, texture, primitive, obj, light, ies};
 
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
@@ -18,6 +18,15 @@ enum Vec3 {
 	Explicit(f32, f32, f32),
 }
 
+/// Index of refraction, either constant or given by the coefficients of a dispersion formula
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Ior {
+	Constant(f32),
+	Cauchy { a: f32, b: f32 },
+	Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
 #[derive(Deserialize, Debug)]
 struct Scene {
 	bsdfs: Vec<Bsdf>,
@@ -29,17 +38,35 @@ struct Scene {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Bsdf {
 	Lambert { name: String, albedo: Texture },
+	OrenNayar { name: String, albedo: Texture, roughness: Texture },
+	Sheen { name: String, albedo: Texture, roughness: Texture },
 	Mirror { name: String, albedo: Texture },
-	Conductor { name: String, albedo: Texture, material: String },
+	Conductor { name: String, albedo: Texture, material: Option<String>, eta: Option<Vec3>, k: Option<Vec3>, film_thickness: Option<Texture>, film_ior: Option<f32> },
 	Plastic { name: String, albedo: Texture, ior: f32 },
-	Dielectric { name: String, albedo: Texture, ior: f32 },
-	RoughDielectric { name: String, albedo: Texture, ior: f32, roughness: Texture },
-	RoughConductor { name: String, albedo: Texture, material: String, roughness: Texture },
-	RoughPlastic { name: String, albedo: Texture, ior: f32, roughness: Texture },
+	Dielectric { name: String, albedo: Texture, ior: Ior, film_thickness: Option<Texture>, film_ior: Option<f32> },
+	RoughDielectric { name: String, albedo: Texture, ior: Ior, roughness: Texture, roughness_v: Option<Texture> },
+	RoughConductor { name: String, albedo: Texture, material: Option<String>, eta: Option<Vec3>, k: Option<Vec3>, roughness: Texture, roughness_v: Option<Texture>, film_thickness: Option<Texture>, film_ior: Option<f32> },
+	RoughPlastic { name: String, albedo: Texture, ior: f32, roughness: Texture, roughness_v: Option<Texture> },
 	SmoothCoat { name: String, ior: f32, sigma_a: Vec3, thickness: f32, substrate: Box<BsdfRef> },
-	Transparency { name: String },
-	Thinsheet { name: String, albedo: Texture, ior: f32 },
+	RoughCoat { name: String, ior: f32, sigma_a: Vec3, thickness: f32, substrate: Box<BsdfRef>, roughness: Texture, roughness_v: Option<Texture> },
+	Transparency { name: String, base: Box<BsdfRef>, alpha: Texture },
+	TwoSided { name: String, base: Box<BsdfRef> },
+	Mixed { name: String, bsdf0: Box<BsdfRef>, bsdf1: Box<BsdfRef>, ratio: Texture },
+	Thinsheet { name: String, albedo: Texture, ior: f32, thickness: Option<f32>, sigma_a: Option<Vec3>, enable_interference: Option<bool> },
 	Null { name: String },
+	/// Binary file of the MERL BRDF database
+	Measured { name: String, file: String },
+	Principled {
+		name: String,
+		base_color: Texture,
+		metallic: Option<Texture>,
+		roughness: Option<Texture>,
+		specular: Option<Texture>,
+		sheen: Option<Texture>,
+		clearcoat: Option<Texture>,
+		transmission: Option<Texture>,
+		ior: Option<f32>,
+	},
 }
 
 #[derive(Deserialize, Debug)]
@@ -61,14 +88,59 @@ enum Texture {
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ProceduralTexture {
 	Checker { on_color: Vec3, off_color: Vec3, res_u: f32, res_v: f32 },
+	Perlin(NoiseParams),
+	Fbm(NoiseParams),
+	Turbulence(NoiseParams),
+	Voronoi(NoiseParams),
+	Marble(NoiseParams),
+	Wood(NoiseParams),
+	/// Blend from the bottom to the top color along the Y axis of the transform, for backgrounds only
+	Gradient { top: Vec3, bottom: Vec3 },
+}
+
+#[derive(Deserialize, Debug)]
+struct NoiseParams {
+	frequency: Option<f32>,
+	octaves: Option<u32>,
+	/// Evaluate the noise at world positions rather than texture coordinates
+	solid: Option<bool>,
+	/// Colors at increasing noise values, from black to white by default
+	ramp: Option<Vec<(f32, Vec3)>>,
+}
+
+impl NoiseParams {
+	fn convert(self, noise: texture::Noise) -> texture::Texture {
+		texture::Texture::Noise {
+			noise,
+			frequency: self.frequency.unwrap_or(1.0),
+			octaves: self.octaves.unwrap_or(4),
+			solid: self.solid.unwrap_or(false),
+			ramp: match self.ramp {
+				Some(stops) => texture::ColorRamp::new(stops.into_iter().map(|(t, c)| (t, c.convert())).collect()),
+				None => texture::ColorRamp::gray(),
+			},
+		}
+	}
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Primitive {
-	Quad { bsdf: BsdfRef, transform: Transform, emission: Option<Vec3> },
-	Mesh { bsdf: BsdfRef, transform: Transform, file: String },
-	InfiniteSphere { transform: Transform, emission: String },
+	Quad { bsdf: BsdfRef, transform: Transform, emission: Option<Texture>, ies: Option<String>, bump: Option<Texture>, bump_strength: Option<f32>, normal_map: Option<String> },
+	/// Point light, turned into a spot light pointing toward -Y if `spot_angle` (half-angle
+	/// of the cone, in degrees) is given
+	Point { transform: Transform, power: Vec3, ies: Option<String>, spot_angle: Option<f32>, spot_falloff: Option<f32> },
+	Mesh { bsdf: BsdfRef, transform: Transform, file: String, emission: Option<Texture>, bump: Option<Texture>, bump_strength: Option<f32>, normal_map: Option<String> },
+	InfiniteSphere { transform: Transform, emission: Texture, mapping: Option<Mapping>, scale: Option<f32>, visible: Option<bool>, portals: Option<Vec<Transform>> },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Mapping {
+	Equirectangular,
+	CubeCross,
+	MirrorBall,
+	Angular,
 }
 
 #[derive(Deserialize, Debug)]
@@ -111,6 +183,16 @@ impl Vec3 {
 	}
 }
 
+impl Ior {
+	fn convert(self) -> material::DielectricIOR {
+		match self {
+			Ior::Constant(ior) => material::DielectricIOR::Constant(ior),
+			Ior::Cauchy { a, b } => material::DielectricIOR::Cauchy(a, b),
+			Ior::Sellmeier { b, c } => material::DielectricIOR::Sellmeier(b, c),
+		}
+	}
+}
+
 impl Scene {
 	fn convert(self, dir: &Path) -> (scene::Scene, camera::Camera) {
 		let mut bsdfs = HashMap::new();
@@ -119,11 +201,14 @@ impl Scene {
 			bsdfs.insert(name, mat);
 		}
 		let mut objects = Vec::new();
-		let mut envmap = None;
+		let mut background = None;
+		let mut background_visible = true;
 		for p in self.primitives {
-			p.convert(dir, &bsdfs, &mut objects, &mut envmap);
+			p.convert(dir, &bsdfs, &mut objects, &mut background, &mut background_visible);
 		}
-		(scene::Scene::new(envmap, objects), self.camera.convert())
+		let mut scene = scene::Scene::new(background, objects);
+		scene.set_background_visible(background_visible);
+		(scene, self.camera.convert())
 	}
 }
 
@@ -135,11 +220,26 @@ impl Bsdf {
 					albedo: albedo.convert(dir),
 				}))
 			}
-			Bsdf::RoughPlastic { name, albedo, ior, roughness } => {
+			Bsdf::Sheen { name, albedo, roughness } => {
+				(name.clone(), Arc::new(material::Sheen {
+					albedo: albedo.convert(dir),
+					roughness: roughness.convert(dir),
+				}))
+			}
+			Bsdf::Measured { name, file } => {
+				(name.clone(), Arc::new(material::Measured::load_merl(dir.join(&file))))
+			}
+			Bsdf::OrenNayar { name, albedo, roughness } => {
+				(name.clone(), Arc::new(material::OrenNayar {
+					albedo: albedo.convert(dir),
+					sigma: roughness.convert(dir),
+				}))
+			}
+			Bsdf::RoughPlastic { name, albedo, ior, roughness, roughness_v } => {
 				(name.clone(), Arc::new(material::RoughPlastic::new(
 					albedo.convert(dir),
 					ior,
-					roughness.convert(dir),
+					convert_roughness(roughness, roughness_v, dir),
 				)))
 			}
 			Bsdf::Mirror { name, albedo } => {
@@ -147,40 +247,51 @@ impl Bsdf {
 					albedo: albedo.convert(dir),
 				}))
 			}
-			Bsdf::Conductor { name, albedo, material } => {
-				(name.clone(), material::Conductor::from_symbol(&material, albedo.convert(dir)).unwrap())
+			Bsdf::Conductor { name, albedo, material, eta, k, film_thickness, film_ior } => {
+				(name.clone(), Arc::new(material::Conductor {
+					albedo: albedo.convert(dir),
+					ior: convert_conductor_ior(material, eta, k, dir),
+					thin_film: convert_thin_film(film_thickness, film_ior, dir),
+				}))
 			}
-			Bsdf::RoughConductor { name, albedo, material, roughness } => {
-				(name.clone(), material::RoughConductor::from_symbol(&material, albedo.convert(dir), roughness.convert(dir)).unwrap())
+			Bsdf::RoughConductor { name, albedo, material, eta, k, roughness, roughness_v, film_thickness, film_ior } => {
+				(name.clone(), Arc::new(material::RoughConductor {
+					albedo: albedo.convert(dir),
+					ior: convert_conductor_ior(material, eta, k, dir),
+					roughness: convert_roughness(roughness, roughness_v, dir),
+					thin_film: convert_thin_film(film_thickness, film_ior, dir),
+				}))
 			}
 			Bsdf::Plastic { name, albedo, ior } => {
 				(name.clone(), Arc::new(material::Plastic::new(albedo.convert(dir), ior)))
 			}
-			Bsdf::Dielectric { name, albedo, ior } => {
+			Bsdf::Dielectric { name, albedo, ior, film_thickness, film_ior } => {
 				(name.clone(), Arc::new(material::Dielectric {
 					albedo: albedo.convert(dir),
-					ior,
+					ior: ior.convert(),
+					thin_film: convert_thin_film(film_thickness, film_ior, dir),
 				}))
 			}
-			Bsdf::RoughDielectric { name, albedo, ior, roughness } => {
+			Bsdf::RoughDielectric { name, albedo, ior, roughness, roughness_v } => {
 				(name.clone(), Arc::new(material::RoughDielectric {
 					albedo: albedo.convert(dir),
-					ior,
-					roughness: roughness.convert(dir),
+					ior: ior.convert(),
+					roughness: convert_roughness(roughness, roughness_v, dir),
 				}))
 			}
-			Bsdf::Thinsheet { name, albedo, ior } => {
-				// TODO: real thin sheet
-				(name.clone(), Arc::new(material::Dielectric {
+			Bsdf::Thinsheet { name, albedo, ior, thickness, sigma_a, enable_interference } => {
+				// Tungsten expresses the thickness in units of 500nm
+				let thickness = thickness.unwrap_or(0.5);
+				let sigma_a = sigma_a.map_or(math::Vec3::zero(), |v| v.convert());
+				(name.clone(), Arc::new(material::ThinDielectric {
 					albedo: albedo.convert(dir),
 					ior,
+					scaled_sigma_a: sigma_a * thickness,
+					film_thickness: if enable_interference.unwrap_or(false) { Some(thickness * 500.0) } else { None },
 				}))
 			}
 			Bsdf::Null { name } => {
-				// TODO: real null bsdf
-				(name.clone(), Arc::new(material::Diffuse {
-					albedo: texture::Texture::Constant(math::Vec3::zero()),
-				}))
+				(name.clone(), Arc::new(material::Null))
 			}
 
 			Bsdf::SmoothCoat { name, ior, sigma_a, thickness, substrate } => {
@@ -190,10 +301,45 @@ impl Bsdf {
 					substrate: substrate.convert(dir, bsdfs),
 				}))
 			}
-			Bsdf::Transparency { name } => {
-				// TODO: add material
-				(name.clone(), Arc::new(material::Diffuse {
-					albedo: texture::Texture::Constant(math::Vec3::thrice(0.5)),
+			Bsdf::Principled { name, base_color, metallic, roughness, specular, sheen, clearcoat, transmission, ior } => {
+				let scalar = |t: Option<Texture>, default: f32| {
+					t.map_or(texture::Texture::Constant(math::Vec3::thrice(default)), |t| t.convert(dir))
+				};
+				(name.clone(), Arc::new(material::Principled {
+					base_color: base_color.convert(dir),
+					metallic: scalar(metallic, 0.0),
+					roughness: scalar(roughness, 0.5),
+					specular: scalar(specular, 0.5),
+					sheen: scalar(sheen, 0.0),
+					clearcoat: scalar(clearcoat, 0.0),
+					transmission: scalar(transmission, 0.0),
+					ior: ior.unwrap_or(1.5),
+				}))
+			}
+			Bsdf::RoughCoat { name, ior, sigma_a, thickness, substrate, roughness, roughness_v } => {
+				(name.clone(), Arc::new(material::RoughCoat {
+					ior,
+					scaled_sigma_a: sigma_a.convert() * thickness,
+					roughness: convert_roughness(roughness, roughness_v, dir),
+					substrate: substrate.convert(dir, bsdfs),
+				}))
+			}
+			Bsdf::Transparency { name, base, alpha } => {
+				(name.clone(), Arc::new(material::Transparency {
+					opacity: alpha.convert(dir),
+					base: base.convert(dir, bsdfs),
+				}))
+			}
+			Bsdf::TwoSided { name, base } => {
+				(name.clone(), Arc::new(material::TwoSided {
+					base: base.convert(dir, bsdfs),
+				}))
+			}
+			Bsdf::Mixed { name, bsdf0, bsdf1, ratio } => {
+				(name.clone(), Arc::new(material::Mix {
+					weight: ratio.convert(dir),
+					first: bsdf0.convert(dir, bsdfs),
+					second: bsdf1.convert(dir, bsdfs),
 				}))
 			}
 		}
@@ -215,6 +361,59 @@ impl BsdfRef {
 	}
 }
 
+/// The roughness is isotropic, unless a distinct roughness along v is given
+fn convert_roughness(roughness: Texture, roughness_v: Option<Texture>, dir: &Path) -> material::Roughness {
+	match roughness_v {
+		None => material::Roughness::Isotropic(roughness.convert(dir)),
+		Some(roughness_v) => material::Roughness::Anisotropic(roughness.convert(dir), roughness_v.convert(dir)),
+	}
+}
+
+/// A thin film coating is added when its thickness (in nanometers) is given
+fn convert_thin_film(thickness: Option<Texture>, ior: Option<f32>, dir: &Path) -> Option<material::ThinFilm> {
+	thickness.map(|thickness| material::ThinFilm {
+		thickness: thickness.convert(dir),
+		ior: ior.unwrap_or(1.5),
+	})
+}
+
+/// Explicit `eta` and `k` take precedence over the material, which is either the
+/// symbol of a tabulated conductor or a file of measured spectral data
+fn convert_conductor_ior(material: Option<String>, eta: Option<Vec3>, k: Option<Vec3>, dir: &Path) -> material::ComplexIOR {
+	if let (Some(eta), Some(k)) = (eta, k) {
+		return material::ComplexIOR { eta: eta.convert(), k: k.convert() };
+	}
+	let material = material.unwrap_or_else(|| "Cu".to_owned());
+	match material::ComplexIOR::from_symbol(&material) {
+		Some(ior) => ior,
+		None => {
+			let path = dir.join(&material);
+			if !path.is_file() {
+				panic!("unknown conductor '{}'", material);
+			}
+			material::ComplexIOR::load(path)
+		}
+	}
+}
+
+/// Perturb the shading normal of a material with either a normal map or a bump map,
+/// both stored without gamma encoding
+fn convert_normal_map(material: Arc<material::Material>, bump: Option<Texture>, bump_strength: Option<f32>, normal_map: Option<String>, dir: &Path) -> Arc<material::Material> {
+	let normal_map = match (normal_map, bump) {
+		(Some(file), None) => material::NormalMap::Tangent(texture::Texture::Bitmap(texture::Image::load_linear(dir.join(&file)))),
+		(None, Some(bump)) => {
+			let height = match bump {
+				Texture::Bitmap(file) => texture::Texture::Bitmap(texture::Image::load_linear(dir.join(&file))),
+				bump => bump.convert(dir),
+			};
+			material::NormalMap::Bump(height, bump_strength.unwrap_or(1.0))
+		}
+		(None, None) => return material,
+		(Some(_), Some(_)) => panic!("a primitive cannot have both a normal map and a bump map"),
+	};
+	Arc::new(material::NormalMapped { normal_map, base: material })
+}
+
 impl Texture {
 	fn convert(self, dir: &Path) -> texture::Texture {
 		match self {
@@ -226,28 +425,72 @@ impl Texture {
 					resolution: (res_u, res_v)
 				}
 			}
+			Texture::Procedural(ProceduralTexture::Perlin(p)) => p.convert(texture::Noise::Perlin),
+			Texture::Procedural(ProceduralTexture::Fbm(p)) => p.convert(texture::Noise::Fbm),
+			Texture::Procedural(ProceduralTexture::Turbulence(p)) => p.convert(texture::Noise::Turbulence),
+			Texture::Procedural(ProceduralTexture::Voronoi(p)) => p.convert(texture::Noise::Voronoi),
+			Texture::Procedural(ProceduralTexture::Marble(p)) => p.convert(texture::Noise::Marble),
+			Texture::Procedural(ProceduralTexture::Wood(p)) => p.convert(texture::Noise::Wood),
+			Texture::Procedural(ProceduralTexture::Gradient { .. }) => panic!("gradients are only supported as background"),
 			Texture::Bitmap(file) => texture::Texture::Bitmap(texture::Image::load_ldr(dir.join(&file))),
 		}
 	}
 }
 
 impl Primitive {
-	fn convert(self, dir: &Path, bsdfs: &HashMap<String, Arc<material::Material>>, objects: &mut Vec<scene::Object>, envmap: &mut Option<light::EnvMap>) {
+	fn convert(self, dir: &Path, bsdfs: &HashMap<String, Arc<material::Material>>, objects: &mut Vec<scene::Object>, background: &mut Option<Box<light::Light + Send + Sync>>, background_visible: &mut bool) {
 		match self {
-			Primitive::Quad { bsdf, transform, emission: None } => {
+			Primitive::Quad { bsdf, transform, emission: None, bump, bump_strength, normal_map, .. } => {
 				objects.push(scene::Object::Scatterer {
 					surface: Box::new(primitive::Parallelogram::unit_transform(&transform.convert())),
-					material: bsdf.convert(dir, bsdfs),
+					material: convert_normal_map(bsdf.convert(dir, bsdfs), bump, bump_strength, normal_map, dir),
 				})
 			}
-			Primitive::Quad { bsdf: _, transform, emission: Some(v) } => {
+			Primitive::Quad { bsdf, transform, emission: Some(emission), ies, bump, bump_strength, normal_map } => {
+				let base = convert_normal_map(bsdf.convert(dir, bsdfs), bump, bump_strength, normal_map, dir);
+				let v = match emission {
+					Texture::Constant(v) if !base.scatters_light() || ies.is_some() => v,
+					_ if ies.is_some() => panic!("textured emission cannot have an IES profile"),
+					emission => {
+						// the emission is carried by the material, which also scatters light
+						objects.push(scene::Object::EmissiveScatterer(light::EmissiveSurface {
+							surface: Box::new(primitive::Parallelogram::unit_transform(&transform.convert())),
+							material: Arc::new(material::Emissive { emission: emission.convert(dir), base }),
+						}));
+						return;
+					}
+				};
+				if base.scatters_light() {
+					println!("Warning: the bsdf of a light with an IES profile is ignored");
+				}
+				let transform = transform.convert();
 				objects.push(scene::Object::Emitter(light::AreaLight {
-					surface: Box::new(primitive::Parallelogram::unit_transform(&transform.convert())),
+					surface: Box::new(primitive::Parallelogram::unit_transform(&transform)),
 					emission: v.convert(),
+					// the scale of the quad must not distort the profile
+					profile: ies.map(|file| ies::IesProfile::load(dir.join(&file), &transform.rotation())),
 				}))
 			}
-			Primitive::Mesh { bsdf, transform, file } => {
-				let mat = bsdf.convert(dir, bsdfs);
+			Primitive::Point { transform, power, ies, spot_angle, spot_falloff } => {
+				let transform = transform.convert();
+				objects.push(scene::Object::PointEmitter(light::PointLight {
+					position: transform.transform_point(math::Vec3::zero()),
+					intensity: power.convert() * math::INV_4_PI,
+					spot: spot_angle.map(|angle| light::Spot {
+						transform: transform.rotation(),
+						angle,
+						falloff: spot_falloff.unwrap_or(0.0),
+					}),
+					profile: ies.map(|file| ies::IesProfile::load(dir.join(&file), &transform.rotation())),
+				}))
+			}
+			Primitive::Mesh { bsdf, transform, file, emission, bump, bump_strength, normal_map } => {
+				let mut mat = convert_normal_map(bsdf.convert(dir, bsdfs), bump, bump_strength, normal_map, dir);
+				if let Some(emission) = emission {
+					// only parallelograms can be sampled as lights: emissive meshes are merely hit by chance
+					println!("Warning: emissive meshes are not light-sampled, which makes them noisy");
+					mat = Arc::new(material::Emissive { emission: emission.convert(dir), base: mat });
+				}
 				for (_, mesh) in obj::load(dir.join(&file), &transform.convert()) {
 					objects.push(scene::Object::Scatterer {
 						surface: Box::new(mesh),
@@ -255,9 +498,34 @@ impl Primitive {
 					})
 				}
 			}
-			Primitive::InfiniteSphere { transform, emission } => {
-				let hdr = texture::Image::load_hdr(dir.join(&emission));
-				*envmap = Some(light::EnvMap::from_image(hdr, &transform.convert()))
+			Primitive::InfiniteSphere { transform, emission, mapping, scale, visible, portals } => {
+				let scale = scale.unwrap_or(1.0);
+				*background = Some(match emission {
+					Texture::Constant(v) => Box::new(light::ConstantBackground { emission: v.convert() * scale }),
+					Texture::Bitmap(file) => {
+						let hdr = texture::Image::load_hdr(dir.join(&file));
+						let transform = transform.convert();
+						let mut map = match mapping.unwrap_or(Mapping::Equirectangular) {
+							Mapping::Equirectangular => light::EnvMap::from_image(hdr, &transform, scale),
+							Mapping::CubeCross => light::EnvMap::from_cube_cross(hdr, &transform, scale),
+							Mapping::MirrorBall => light::EnvMap::from_light_probe(hdr, light::ProbeMapping::MirrorBall, &transform, scale),
+							Mapping::Angular => light::EnvMap::from_light_probe(hdr, light::ProbeMapping::Angular, &transform, scale),
+						};
+						if let Some(portals) = portals {
+							map.set_portals(portals.into_iter()
+								.map(|t| primitive::Parallelogram::unit_transform(&t.convert()))
+								.collect());
+						}
+						Box::new(map)
+					}
+					Texture::Procedural(ProceduralTexture::Gradient { top, bottom }) => Box::new(light::GradientBackground {
+						top: top.convert() * scale,
+						bottom: bottom.convert() * scale,
+						up: transform.convert().transform_vector(math::Vec3::new(0.0, 1.0, 0.0)),
+					}),
+					Texture::Procedural(_) => panic!("procedural textures are not supported as background"),
+				});
+				*background_visible = visible.unwrap_or(true);
 			}
 		}
 	}
@@ -318,10 +586,13 @@ impl Tonemap {
 }
 
 fn main() {
-	let args = std::env::args().collect::<Vec<String>>();
+	let mut args = std::env::args().collect::<Vec<String>>();
+
+	let spectral = args.iter().any(|a| a == "--spectral");
+	args.retain(|a| a != "--spectral");
 
 	if args.len() < 2 {
-		eprintln!("usage: {} tungsten_scene.json", args[0]);
+		eprintln!("usage: {} [--spectral] tungsten_scene.json", args[0]);
 		std::process::exit(1);
 	}
 
@@ -330,7 +601,8 @@ fn main() {
 	let tungsten_scene: Scene = serde_json::from_reader(file).unwrap();
 	//println!("{:?}", &tungsten_scene);
 
-	let (scene, camera) = tungsten_scene.convert(path.parent().unwrap());
+	let (mut scene, camera) = tungsten_scene.convert(path.parent().unwrap());
+	scene.set_spectral(spectral);
 
 	tracing::render_preview(scene, camera);
 	//tracing::render(scene, camera, 16);
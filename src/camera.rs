@@ -39,15 +39,37 @@ impl Camera {
 	}
 
 	pub fn make_ray(&self, pixel: (usize, usize), img_uv: (f32, f32), lens_uv: (f32, f32)) -> Ray {
+		self.make_ray_differentials(pixel, img_uv, lens_uv).0
+	}
+
+	/// Make a ray along with its differentials, which give its footprint of about one pixel
+	pub fn make_ray_differentials(&self, pixel: (usize, usize), img_uv: (f32, f32), lens_uv: (f32, f32)) -> (Ray, RayDifferentials) {
 		let pj = warp::tent(img_uv);
+		let img_pos = (pixel.0 as f32 + pj.0 + 0.5, pixel.1 as f32 + pj.1 + 0.5);
+		let lj = warp::uniform_disk(lens_uv);
+		let lens_pos = Vec3::new(lj.0 * self.aperture_radius, lj.1 * self.aperture_radius, 0.0);
+
+		// differences with the rays through the same lens point from the neighbor pixels
+		let ray = self.ray_through(img_pos, lens_pos);
+		let rx = self.ray_through((img_pos.0 + 1.0, img_pos.1), lens_pos);
+		let ry = self.ray_through((img_pos.0, img_pos.1 + 1.0), lens_pos);
+		let differentials = RayDifferentials {
+			dp_dx: Vec3::zero(),
+			dp_dy: Vec3::zero(),
+			dd_dx: rx.direction - ray.direction,
+			dd_dy: ry.direction - ray.direction,
+		};
+		(ray, differentials)
+	}
+
+	/// Ray from `lens_pos` through the position `img_pos`, in pixels, on the image plane
+	fn ray_through(&self, img_pos: (f32, f32), lens_pos: Vec3) -> Ray {
 		let img_plane_pos = Vec3 {
-			x: -1.0       + (pixel.0 as f32 + pj.0 + 0.5) * 2.0 * self.pixel_size.0,
-			y: self.ratio - (pixel.1 as f32 + pj.1 + 0.5) * 2.0 * self.pixel_size.0,
+			x: -1.0       + img_pos.0 * 2.0 * self.pixel_size.0,
+			y: self.ratio - img_pos.1 * 2.0 * self.pixel_size.0,
 			z: self.plane_dist,
 		};
 		let focus_plane_pos = img_plane_pos * (self.focus_dist / img_plane_pos.z);
-		let lj = warp::uniform_disk(lens_uv);
-		let lens_pos = Vec3::new(lj.0 * self.aperture_radius, lj.1 * self.aperture_radius, 0.0);
 		let local_dir = (focus_plane_pos - lens_pos).normalized();
 
 		Ray {
@@ -1,4 +1,7 @@
 use rayon::prelude::*;
+use std::cmp::Ordering;
+
+use math::EPSILON;
 
 /// Make an importance samplable 1D distribution
 ///
@@ -32,13 +35,26 @@ fn make1d(pdf: &mut [f32], cdf: &mut [f32]) -> f32 {
 ///
 /// Returns the sampled index and its PDF.
 fn sample1d(pdf: &[f32], cdf: &[f32], u: f32) -> (usize, f32) {
-	let i = match cdf.binary_search_by(|v| v.partial_cmp(&u).unwrap()) {
+	// find the first entry whose CDF is above `u`, so that null entries are never chosen
+	let i = match cdf.binary_search_by(|&v| if v <= u { Ordering::Less } else { Ordering::Greater }) {
 		Ok(v) => v,
 		Err(v) => v,
 	};
+	let i = i.min(cdf.len() - 1);
 	(i, pdf[i])
 }
 
+/// Importance sample a 1D piecewise-constant distribution over [0;1[
+///
+/// Returns the sampled position, the index of its bin and its PDF.
+fn sample1d_continuous(pdf: &[f32], cdf: &[f32], u: f32) -> (f32, usize, f32) {
+	let (i, p) = sample1d(pdf, cdf, u);
+	let cdf_before = if i == 0 { 0.0 } else { cdf[i - 1] };
+	let offset = if p > 0.0 { ((u - cdf_before) / p).max(0.0).min(1.0) } else { 0.5 };
+	let n = pdf.len() as f32;
+	(((i as f32 + offset) / n).min(1.0 - EPSILON), i, p * n)
+}
+
 pub struct Distribution1D {
 	pdf: Vec<f32>,
 	cdf: Vec<f32>,
@@ -55,6 +71,21 @@ impl Distribution1D {
 	pub fn sample(&self, u: f32) -> (usize, f32) {
 		sample1d(&self.pdf[..], &self.cdf[..], u)
 	}
+
+	/// Importance sample the distribution as a piecewise-constant density over [0;1[
+	///
+	/// Returns the sampled position and its PDF.
+	pub fn sample_continuous(&self, u: f32) -> (f32, f32) {
+		let (x, _, p) = sample1d_continuous(&self.pdf[..], &self.cdf[..], u);
+		(x, p)
+	}
+
+	/// PDF of sampling the position `x` of [0;1[
+	pub fn pdf_continuous(&self, x: f32) -> f32 {
+		let n = self.pdf.len();
+		let i = ((x * n as f32) as usize).min(n - 1);
+		self.pdf[i] * n as f32
+	}
 }
 
 pub struct Distribution2D {
@@ -83,19 +114,23 @@ impl Distribution2D {
 		Distribution2D { width, height, conditional_pdf, conditional_cdf, marginal_pdf, marginal_cdf }
 	}
 
-	pub fn sample(&self, u: f32, v: f32) -> ((usize, usize), f32) {
+	/// Importance sample the distribution as a piecewise-constant density over [0;1[²
+	///
+	/// Returns the sampled position and its PDF with respect to the area of [0;1[².
+	pub fn sample(&self, u: f32, v: f32) -> ((f32, f32), f32) {
 		// first sample the marginal distribution to find a row
-		let (s1, p1) = sample1d(&self.marginal_pdf[..], &self.marginal_cdf[..], v);
+		let (y, s1, p1) = sample1d_continuous(&self.marginal_pdf[..], &self.marginal_cdf[..], v);
 		// then sample the distribution of the chosen row `s1`
 		let i0 = self.width * s1;
 		let i1 = i0 + self.width;
-		let (s0, p0) = sample1d(&self.conditional_pdf[i0..i1], &self.conditional_cdf[i0..i1], u);
-		((s0, s1), p0 * p1)
+		let (x, _, p0) = sample1d_continuous(&self.conditional_pdf[i0..i1], &self.conditional_cdf[i0..i1], u);
+		((x, y), p0 * p1)
 	}
 
-	pub fn pdf(&self, x: usize, y: usize) -> f32 {
-		let x = x.min(self.width - 1);
-		let y = y.min(self.height - 1);
-		self.conditional_pdf[y * self.width + x] * self.marginal_pdf[y]
+	/// PDF of sampling the position (`x`, `y`) of [0;1[², with respect to its area
+	pub fn pdf(&self, x: f32, y: f32) -> f32 {
+		let x = ((x * self.width as f32) as usize).min(self.width - 1);
+		let y = ((y * self.height as f32) as usize).min(self.height - 1);
+		self.conditional_pdf[y * self.width + x] * self.marginal_pdf[y] * (self.width * self.height) as f32
 	}
 }
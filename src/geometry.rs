@@ -1,10 +1,40 @@
 use math::*;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default)]
 pub struct Intersection {
 	pub distance: f32,
+	/// Shading normal, which may be interpolated
 	pub normal: Vec3,
+	/// Normal of the actual surface, on the same side as the shading normal
+	pub geometric_normal: Vec3,
+	/// Direction of increasing u on the surface, not necessarily orthogonal to the normal
+	pub tangent: Vec3,
 	pub uv: (f32, f32),
+	/// Partial derivatives of the position with respect to the texture coordinates
+	pub dpdu: Vec3,
+	pub dpdv: Vec3,
+	/// Partial derivatives of the shading normal with respect to the texture coordinates
+	pub dndu: Vec3,
+	pub dndv: Vec3,
+}
+
+impl Intersection {
+	/// Derivatives of the texture coordinates along the offset `dp` on the surface
+	///
+	/// `dp` is projected on the tangent plane in the least squares sense.
+	pub fn uv_derivatives(&self, dp: Vec3) -> (f32, f32) {
+		let a = Vec3::dot(self.dpdu, self.dpdu);
+		let b = Vec3::dot(self.dpdu, self.dpdv);
+		let c = Vec3::dot(self.dpdv, self.dpdv);
+		let det = a * c - b * b;
+		// degenerate when dpdu and dpdv are (nearly) parallel
+		if !(det > 1e-6 * a * c) {
+			return (0.0, 0.0);
+		}
+		let pu = Vec3::dot(self.dpdu, dp);
+		let pv = Vec3::dot(self.dpdv, dp);
+		((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+	}
 }
 
 pub trait Surface {
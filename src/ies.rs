@@ -0,0 +1,241 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use distribution::Distribution2D;
+use math::*;
+
+/// Resolution (in azimuth and elevation) of the tabulated profile
+const RESOLUTION: (usize, usize) = (128, 64);
+
+/// Angular intensity profile of a light fixture, loaded from an IES LM-63 file
+///
+/// In the local frame of the profile, the fixture points downward, along -Y, which
+/// is the photometric nadir. The profile is normalized so that its peak intensity is 1.
+pub struct IesProfile {
+	width: usize,
+	height: usize,
+	table: Vec<f32>,
+	dist: Distribution2D,
+	transform: Mat4,
+	inv_transform: Mat4,
+}
+
+/// Photometric data as stored in a type C IES file
+struct Photometry {
+	vertical_angles: Vec<f32>,
+	horizontal_angles: Vec<f32>,
+	/// Candela values, one row of vertical angles per horizontal angle
+	candelas: Vec<f32>,
+}
+
+impl IesProfile {
+	/// Load an IES LM-63 file, oriented by the rotation `transform`
+	pub fn load<P: AsRef<Path>>(path: P, transform: &Mat4) -> IesProfile {
+		let mut content = String::new();
+		File::open(&path).expect("failed to open IES profile")
+			.read_to_string(&mut content).expect("failed to read IES profile");
+		let photometry = parse(&content);
+
+		// Resample the candela values on a regular grid, as done for environment maps
+		let (width, height) = RESOLUTION;
+		let mut table = vec![0f32; width * height];
+		for y in 0..height {
+			let theta = (y as f32 + 0.5) * 180.0 / height as f32;
+			for x in 0..width {
+				let phi = (x as f32 + 0.5) * 360.0 / width as f32;
+				table[y * width + x] = photometry.candela(phi, theta);
+			}
+		}
+
+		let max = table.iter().cloned().fold(0.0, f32::max);
+		if max > 0.0 {
+			for v in table.iter_mut() {
+				*v /= max;
+			}
+		}
+
+		// The sin(theta) factor counteracts the deformation of the spherical parameterization
+		let mut weights = table.clone();
+		for y in 0..height {
+			let sin_theta = ((y as f32 + 0.5) * PI / height as f32).sin();
+			for w in &mut weights[y * width..(y + 1) * width] {
+				*w *= sin_theta;
+			}
+		}
+		let dist = Distribution2D::new(weights, width, height);
+
+		IesProfile {
+			width,
+			height,
+			table,
+			dist,
+			transform: transform.clone(),
+			inv_transform: transform.inverse(),
+		}
+	}
+
+	/// Relative intensity emitted toward the world space direction `dir`
+	pub fn eval(&self, dir: Vec3) -> f32 {
+		let (tx, ty) = self.direction_to_texel(dir);
+		let tx = tx - 0.5;
+		let ty = (ty - 0.5).max(0.0).min((self.height - 1) as f32);
+
+		let x0 = tx.floor();
+		let y0 = ty.floor();
+		let dx = tx - x0;
+		let dy = ty - y0;
+
+		// wrap around in azimuth, clamp in elevation
+		let w = self.width as isize;
+		let x0 = ((x0 as isize % w + w) % w) as usize;
+		let x1 = (x0 + 1) % self.width;
+		let y0 = y0 as usize;
+		let y1 = (y0 + 1).min(self.height - 1);
+
+		let get = |x, y| self.table[y * self.width + x];
+		let v0 = get(x0, y0) * (1.0 - dx) + get(x1, y0) * dx;
+		let v1 = get(x0, y1) * (1.0 - dx) + get(x1, y1) * dx;
+		v0 * (1.0 - dy) + v1 * dy
+	}
+
+	/// Importance sample an emission direction according to the profile
+	///
+	/// Returns the world space direction and its solid angle density.
+	pub fn sample(&self, (u, v): (f32, f32)) -> (Vec3, f32) {
+		let ((x, y), uv_pdf) = self.dist.sample(u, v);
+		let theta = y * PI;
+		let phi = x * 2.0 * PI;
+
+		let (sin_theta, cos_theta) = theta.sin_cos();
+		let (sin_phi, cos_phi) = phi.sin_cos();
+		let local_dir = Vec3::new(sin_theta * cos_phi, -cos_theta, sin_theta * sin_phi);
+		let dir = self.transform.transform_vector(local_dir).normalized();
+
+		// as in `pdf`, avoid an infinite density at the poles
+		(dir, uv_pdf / (2.0 * PI * PI * sin_theta.max(EPSILON)))
+	}
+
+	/// Solid angle density of sampling the world space direction `dir` with `sample`
+	pub fn pdf(&self, dir: Vec3) -> f32 {
+		let (tx, ty) = self.direction_to_texel(dir);
+		let sin_theta = (ty * PI / self.height as f32).sin().max(EPSILON);
+		self.dist.pdf(tx / self.width as f32, ty / self.height as f32) / (2.0 * PI * PI * sin_theta)
+	}
+
+	/// Convert a world space direction to continuous coordinates in the table
+	fn direction_to_texel(&self, dir: Vec3) -> (f32, f32) {
+		let l = self.inv_transform.transform_vector(dir).normalized();
+		let theta = (-l.y).max(-1.0).min(1.0).acos();
+		let mut phi = l.z.atan2(l.x);
+		if phi < 0.0 {
+			phi += 2.0 * PI;
+		}
+		(phi * INV_2_PI * self.width as f32, theta * INV_PI * self.height as f32)
+	}
+}
+
+impl Photometry {
+	/// Interpolated candela value for the given horizontal and vertical angles (in degrees)
+	fn candela(&self, h: f32, v: f32) -> f32 {
+		let vs = &self.vertical_angles;
+		if v < vs[0] || v > vs[vs.len() - 1] {
+			return 0.0;
+		}
+
+		// Fold the horizontal angle according to the symmetry of the fixture
+		let h = match *self.horizontal_angles.last().unwrap() {
+			l if l == 0.0 => 0.0,
+			l if l == 90.0 => {
+				let h = if h > 180.0 { 360.0 - h } else { h };
+				if h > 90.0 { 180.0 - h } else { h }
+			}
+			l if l == 180.0 => if h > 180.0 { 360.0 - h } else { h },
+			_ => h,
+		};
+
+		let (i, ti) = lookup(&self.horizontal_angles, h);
+		let (j, tj) = lookup(vs, v);
+		let n = vs.len();
+		let i1 = (i + 1).min(self.horizontal_angles.len() - 1);
+		let j1 = (j + 1).min(n - 1);
+
+		let get = |i: usize, j: usize| self.candelas[i * n + j];
+		let c0 = get(i, j) * (1.0 - tj) + get(i, j1) * tj;
+		let c1 = get(i1, j) * (1.0 - tj) + get(i1, j1) * tj;
+		c0 * (1.0 - ti) + c1 * ti
+	}
+}
+
+/// Find the interval of the sorted `angles` containing `x`
+///
+/// Returns the index of its lower bound and the interpolation factor within it.
+fn lookup(angles: &[f32], x: f32) -> (usize, f32) {
+	if angles.len() == 1 || x <= angles[0] {
+		return (0, 0.0);
+	}
+	let i = match angles.iter().position(|&a| a > x) {
+		Some(i) => i - 1,
+		None => return (angles.len() - 1, 0.0),
+	};
+	(i, (x - angles[i]) / (angles[i + 1] - angles[i]))
+}
+
+fn parse(content: &str) -> Photometry {
+	// Skip the header up to the TILT line
+	let mut lines = content.lines();
+	let tilt = loop {
+		let line = lines.next().expect("invalid IES profile: missing TILT line");
+		if line.trim_start().starts_with("TILT=") {
+			break line.trim_start()["TILT=".len()..].trim().to_owned();
+		}
+	};
+
+	let mut values = lines
+		.flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+		.filter(|s| !s.is_empty())
+		.map(|s| s.parse::<f32>().expect("invalid IES profile: malformed number"));
+	let mut next = || values.next().expect("invalid IES profile: unexpected end of file");
+
+	match tilt.as_str() {
+		"NONE" => {}
+		"INCLUDE" => {
+			// lamp-to-luminaire geometry, then the tilt angles and multipliers
+			let _ = next();
+			let nb_pairs = next() as usize;
+			for _ in 0..2 * nb_pairs {
+				let _ = next();
+			}
+		}
+		_ => println!("Warning: external IES tilt file '{}' is ignored", tilt),
+	}
+
+	let _nb_lamps = next();
+	let _lumens_per_lamp = next();
+	let multiplier = next();
+	let nb_vertical = next() as usize;
+	let nb_horizontal = next() as usize;
+	let photometric_type = next() as u32;
+	// units, width, length, height, ballast factor, future use, input watts
+	for _ in 0..7 {
+		let _ = next();
+	}
+
+	if photometric_type != 1 {
+		println!("Warning: IES photometric type {} is not supported, assuming type C", photometric_type);
+	}
+
+	let vertical_angles: Vec<f32> = (0..nb_vertical).map(|_| next()).collect();
+	let mut horizontal_angles: Vec<f32> = (0..nb_horizontal).map(|_| next()).collect();
+	let mut candelas: Vec<f32> = (0..nb_vertical * nb_horizontal).map(|_| next() * multiplier).collect();
+
+	// Close the azimuthal range of complete profiles that stop short of 360 degrees
+	let last = *horizontal_angles.last().unwrap();
+	if last > 180.0 && last < 360.0 && horizontal_angles[0] == 0.0 {
+		horizontal_angles.push(360.0);
+		let first_row = candelas[..nb_vertical].to_vec();
+		candelas.extend(first_row);
+	}
+
+	Photometry { vertical_angles, horizontal_angles, candelas }
+}
@@ -5,31 +5,55 @@ use texture::*;
 use material::*;
 use warp::*;
 use light::*;
+use spectrum;
 
 use rand::Rng;
 
-pub fn estimate_radiance<R: Rng>(scene: &Scene, ray: Ray, rng: &mut R) -> Vec3 {
+/// Estimate the radiance coming along `ray`, whose differentials, if known, are used to
+/// filter textures
+pub fn estimate_radiance<R: Rng>(scene: &Scene, ray: Ray, differentials: Option<RayDifferentials>, rng: &mut R) -> Vec3 {
 	let nb_lights = scene.nb_lights();
 	let light_pick_prob = 1.0 / nb_lights as f32;
 
 	let mut path_weight = Vec3::thrice(1.0);
 	let mut radiance = Vec3::zero();
 	let mut ray = ray;
+	let mut differentials = differentials;
 	let mut specular_bounce = true;
 	let mut last_pdf_dir = 1.0;
+	// origin of the last bounce and distance travelled since then through transparent surfaces
+	let mut last_origin = ray.origin;
+	let mut skipped_dist = 0.0;
+	let mut nb_bounces = 0;
 
-	for nb_bounces in 0.. {
-		let (intersection, material) = match scene.intersect(ray) {
-			Some(Hit::Scatterer(its, mat)) => (its, mat),
+	// In spectral mode, the channels of colors carried along the path hold the values at
+	// three wavelengths rather than RGB, and the materials and lights are upsampled to them
+	let spectral = scene.is_spectral();
+	let lambdas = if spectral {
+		spectrum::sample_wavelengths(rng.gen())
+	} else {
+		Vec3::thrice(spectrum::REFERENCE_WAVELENGTH)
+	};
+	let hero = lambdas.x;
+	let to_path = |rgb: Vec3| if spectral { spectrum::rgb_to_spectral(rgb, lambdas) } else { rgb };
+	let mut single_wavelength = false;
+
+	loop {
+		let (intersection, material, surface_light) = match scene.intersect(ray) {
+			Some(Hit::Scatterer(its, mat, light)) => (its, mat, light),
 			Some(Hit::Emitter(light, dist)) => {
+				if nb_bounces == 0 && dist == INFINITY && !scene.is_background_visible() {
+					break;
+				}
+
 				let contrib = light.eval_direct(ray.direction);
 				let mis_weight = if !specular_bounce {
-					let direct_pdf = light.pdf_direct(ray.direction, dist);
+					let direct_pdf = light.pdf_direct(last_origin, ray.direction, skipped_dist + dist);
 					mis2(last_pdf_dir, direct_pdf * light_pick_prob)
 				} else {
 					1.0
 				};
-				radiance += path_weight * mis_weight * contrib;
+				radiance += path_weight * mis_weight * to_path(contrib);
 
 				// lights do not reflect; stop here
 				break;
@@ -37,12 +61,59 @@ pub fn estimate_radiance<R: Rng>(scene: &Scene, ray: Ray, rng: &mut R) -> Vec3 {
 			None => break,
 		};
 
-		// compute some geometry at intersection
-		let normal = intersection.normal;
+		// compute some geometry at intersection; the shading normal, possibly interpolated or
+		// perturbed by the material, is only used to evaluate the material
+		let mut normal = intersection.geometric_normal;
+		if material.is_two_sided() && Vec3::dot(normal, ray.direction) > 0.0 {
+			// light is then gathered on the side of the hit
+			normal = -normal;
+		}
 		let hit = ray.point_at(intersection.distance);
-		let shading_frame = Frame::from_up(normal);
+		let mut tc = TexCoords::new(intersection.uv, hit);
+		// footprint of the ray on the surface
+		let differentials_at_hit = differentials.map(|d| d.transfer(ray, intersection.distance, intersection.geometric_normal));
+		if let Some(d) = differentials_at_hit {
+			tc.duv_dx = intersection.uv_derivatives(d.dp_dx);
+			tc.duv_dy = intersection.uv_derivatives(d.dp_dy);
+		}
+		let shading_frame = {
+			let frame = Frame::from_up_tangent(intersection.normal, intersection.tangent);
+			match material.shading_normal(tc) {
+				Some(n) => Frame::from_up_tangent(frame.to_world(n).normalized(), intersection.tangent),
+				None => frame,
+			}
+		};
 		let local_in = shading_frame.to_local(ray.direction);
 
+		// stochastically go through partially transparent surfaces, without counting a bounce
+		let opacity = material.opacity(tc);
+		if opacity < 1.0 && rng.gen::<f32>() >= opacity {
+			skipped_dist += intersection.distance;
+			let eps = if Vec3::dot(normal, ray.direction) >= 0.0 { EPSILON } else { -EPSILON };
+			ray.origin = hit + normal * eps * 2.0;
+			differentials = differentials_at_hit;
+			continue;
+		}
+
+		// emissive materials both glow and scatter light
+		let emission = material.emission(tc);
+		if emission != Vec3::zero() {
+			let mis_weight = match surface_light {
+				Some(light) if !specular_bounce => {
+					let direct_pdf = light.pdf_direct(last_origin, ray.direction, skipped_dist + intersection.distance);
+					mis2(last_pdf_dir, direct_pdf * light_pick_prob)
+				}
+				_ => 1.0,
+			};
+			radiance += path_weight * mis_weight * to_path(emission);
+		}
+
+		if spectral && !single_wavelength && material.is_dispersive() {
+			// the other wavelengths would scatter in other directions: only keep the hero one
+			path_weight = Vec3::new(path_weight.x * 3.0, 0.0, 0.0);
+			single_wavelength = true;
+		}
+
 		let cont_prob = path_weight.max_elem().min(1.0);
 
 		if !material.is_purely_specular() && nb_lights > 0 {
@@ -50,24 +121,43 @@ pub fn estimate_radiance<R: Rng>(scene: &Scene, ray: Ray, rng: &mut R) -> Vec3 {
 			let light_idx = (nb_lights as f32 * rng.gen::<f32>()) as usize;
 			if let Some(ref light) = scene.get_light(light_idx) {
 				let (emission, light_sample) = light.sample_direct(hit, rng.gen());
-				if !scene.occluded(hit, normal, light_sample.dir, light_sample.dist) {
-					let local_out = shading_frame.to_local(light_sample.dir);
-					let bsdf_eval = material.eval(local_in, local_out, intersection.uv);
-					let bsdf_pdf = material.pdf(local_in, local_out, intersection.uv);
-					let mis_weight = mis2(light_sample.pdf * light_pick_prob, bsdf_pdf * cont_prob);
-					radiance += path_weight * emission * bsdf_eval * (mis_weight / (light_sample.pdf * light_pick_prob));
+				let transmittance = scene.transmittance(hit, normal, light_sample.dir, light_sample.dist);
+				let local_out = shading_frame.to_local(light_sample.dir);
+				if transmittance > 0.0 && same_sides(normal, ray.direction, light_sample.dir, local_in, local_out) {
+					let bsdf_eval = material.eval_wavelength(local_in, local_out, tc, hero);
+					let bsdf_pdf = material.pdf_wavelength(local_in, local_out, tc, hero);
+					let mis_weight = if light.is_delta() {
+						1.0
+					} else {
+						mis2(light_sample.pdf * light_pick_prob, bsdf_pdf * cont_prob)
+					};
+					radiance += path_weight * to_path(emission) * to_path(bsdf_eval) * (transmittance * mis_weight / (light_sample.pdf * light_pick_prob));
 				}
 			}
 		}
 
-		let bsdf_sample = material.sample(local_in, intersection.uv, Vec3::new(rng.gen(), rng.gen(), rng.gen()));
+		let bsdf_sample = material.sample_wavelength(local_in, tc, Vec3::new(rng.gen(), rng.gen(), rng.gen()), hero);
 
 		if bsdf_sample.weight == Vec3::zero() {
 			break;
 		}
 
+		let dir_out = shading_frame.to_world(bsdf_sample.direction).normalized();
+		if !same_sides(normal, ray.direction, dir_out, local_in, bsdf_sample.direction) {
+			break;
+		}
+
 		last_pdf_dir = bsdf_sample.pdf;
 
+		// the footprint of rays scattered by rough surfaces is too wide to be useful; only keep
+		// track of it through specular bounces
+		differentials = match differentials_at_hit {
+			Some(d) if bsdf_sample.is_specular => {
+				Some(specular_differentials(d, &intersection, tc, &shading_frame, ray.direction, dir_out, local_in, bsdf_sample.direction))
+			}
+			_ => None,
+		};
+
 		// possibly terminate path
 		//if nb_bounces >= 3 {
 			if nb_bounces > 64 {
@@ -85,19 +175,285 @@ pub fn estimate_radiance<R: Rng>(scene: &Scene, ray: Ray, rng: &mut R) -> Vec3 {
 			}
 		//}
 
-		path_weight *= bsdf_sample.weight;
+		path_weight *= to_path(bsdf_sample.weight);
 		specular_bounce = bsdf_sample.is_specular;
 
 		// nudge ray origin to avoid self-intersection
-		ray.direction = shading_frame.to_world(bsdf_sample.direction).normalized();
+		ray.direction = dir_out;
 		let eps = if Vec3::dot(normal, ray.direction) >= 0.0 { EPSILON } else { -EPSILON };
 		ray.origin = hit + normal * eps * 2.0;
+		last_origin = ray.origin;
+		skipped_dist = 0.0;
+		nb_bounces += 1;
 	}
 
-	radiance
+	if spectral {
+		spectrum::spectral_to_rgb(radiance, lambdas)
+	} else {
+		radiance
+	}
+}
+
+/// Whether the geometric and shading normals agree on the reflection or transmission of light
+/// going from `dir_in` to `dir_out`
+///
+/// Paths where they do not would leak light through surfaces, or darken them with black spots.
+fn same_sides(geometric_normal: Vec3, dir_in: Vec3, dir_out: Vec3, local_in: Vec3, local_out: Vec3) -> bool {
+	let geometric = Vec3::dot(geometric_normal, dir_in) * Vec3::dot(geometric_normal, dir_out);
+	let shading = local_in.y * local_out.y;
+	(geometric > 0.0) == (shading > 0.0)
+}
+
+/// Differentials of a ray going along `dir_in` and scattered in the specular direction `dir_out`
+///
+/// Reflections and refractions are told apart from the local directions, and so is the
+/// relative index of refraction, since refraction scales the tangential component.
+fn specular_differentials(differentials: RayDifferentials, its: &Intersection, tc: TexCoords, shading_frame: &Frame, dir_in: Vec3, dir_out: Vec3, local_in: Vec3, local_out: Vec3) -> RayDifferentials {
+	let normal = shading_frame.to_world(Vec3::new(0.0, 1.0, 0.0));
+	let dn_dx = its.dndu * tc.duv_dx.0 + its.dndv * tc.duv_dx.1;
+	let dn_dy = its.dndu * tc.duv_dy.0 + its.dndv * tc.duv_dy.1;
+	if local_in.y * local_out.y < 0.0 {
+		differentials.reflect(dir_in, normal, dn_dx, dn_dy)
+	} else {
+		let sin_in = (local_in.x * local_in.x + local_in.z * local_in.z).sqrt();
+		let sin_out = (local_out.x * local_out.x + local_out.z * local_out.z).sqrt();
+		let eta = if sin_in > 1e-6 { sin_out / sin_in } else { 1.0 };
+		differentials.refract(dir_in, dir_out, eta, normal, dn_dx, dn_dy)
+	}
 }
 
 fn mis2(sample_pdf: f32, other_pdf: f32) -> f32 {
 	let power = |x| x*x;
 	power(sample_pdf) / (power(sample_pdf) + power(other_pdf))
 }
+
+/// Average radiance of a unit sphere lit by a uniform white background, seen from outside
+#[cfg(test)]
+fn furnace_radiance(material: ::std::sync::Arc<Material>, spectral: bool) -> Vec3 {
+	use rand::{SeedableRng, XorShiftRng};
+	use primitive::Sphere;
+
+	let objects = vec![Object::Scatterer { surface: Box::new(Sphere::new(1.0, Vec3::zero())), material }];
+	let mut scene = Scene::new(Some(Box::new(ConstantBackground { emission: Vec3::thrice(1.0) })), objects);
+	scene.set_spectral(spectral);
+
+	let mut rng = XorShiftRng::from_seed([0, 1, 2, 3]);
+	let n = 40000;
+	let mut sum = Vec3::zero();
+	for _ in 0..n {
+		// parallel rays all hitting the sphere
+		let (x, y) = uniform_disk(rng.gen());
+		let ray = Ray { origin: Vec3::new(0.999 * x, 0.999 * y, -3.0), direction: Vec3::new(0.0, 0.0, 1.0) };
+		sum += estimate_radiance(&scene, ray, None, &mut rng);
+	}
+	sum / n as f32
+}
+
+#[test]
+fn test_white_furnace_sphere() {
+	use std::sync::Arc;
+
+	let constant = |v: f32| Texture::Constant(Vec3::thrice(v));
+	let diffuse = |albedo: f32| -> Arc<Material> { Arc::new(Diffuse { albedo: constant(albedo) }) };
+	let perfect_conductor = ComplexIOR { eta: Vec3::zero(), k: Vec3::thrice(1.0) };
+	let roughness = || Roughness::Isotropic(constant(0.5));
+	let (res_h, res_d, res_phi) = MERL_RES;
+
+	// Under a uniform white illumination, the radiance leaving the object is the fraction of the
+	// light it receives that it does not absorb: one for non-absorbing materials.
+	let cases: Vec<(&str, Arc<Material>, f32)> = vec![
+		("diffuse", diffuse(0.5), 0.5),
+		("white diffuse", diffuse(1.0), 1.0),
+		("mirror", Arc::new(Mirror { albedo: constant(1.0) }), 1.0),
+		("conductor", Arc::new(Conductor { albedo: constant(1.0), ior: perfect_conductor, thin_film: None }), 1.0),
+		("plastic", Arc::new(Plastic::new(constant(1.0), 1.5)), 1.0),
+		("rough plastic", Arc::new(RoughPlastic::new(constant(1.0), 1.5, roughness())), 1.0),
+		// a constant table is a Lambertian BRDF
+		("measured", Arc::new(Measured::from_table(vec![Vec3::thrice(INV_PI); res_h * res_d * res_phi])), 1.0),
+		("null", Arc::new(Null), 1.0),
+		("transparency", Arc::new(Transparency { opacity: constant(0.5), base: Arc::new(TwoSided { base: diffuse(1.0) }) }), 1.0),
+		("mix", Arc::new(Mix { weight: constant(0.5), first: diffuse(0.5), second: Arc::new(Mirror { albedo: constant(1.0) }) }), 0.75),
+		("dielectric", Arc::new(Dielectric { albedo: constant(1.0), ior: DielectricIOR::Constant(1.5), thin_film: None }), 1.0),
+		("thin dielectric", Arc::new(ThinDielectric { albedo: constant(1.0), ior: 1.5, scaled_sigma_a: Vec3::zero(), film_thickness: None }), 1.0),
+		("rough conductor", Arc::new(RoughConductor { albedo: constant(1.0), ior: perfect_conductor, roughness: Roughness::Isotropic(constant(0.5)), thin_film: None }), 1.0),
+		("rough dielectric", Arc::new(RoughDielectric { albedo: constant(1.0), ior: DielectricIOR::Constant(1.5), roughness: Roughness::Isotropic(constant(0.5)) }), 1.0),
+	];
+	// materials which lose energy by design, without a known albedo
+	let lossy: Vec<(&str, Arc<Material>)> = vec![
+		("smooth coat", Arc::new(SmoothCoat { ior: 1.5, scaled_sigma_a: Vec3::zero(), substrate: diffuse(1.0) })),
+		("rough coat", Arc::new(RoughCoat { ior: 1.5, scaled_sigma_a: Vec3::zero(), roughness: roughness(), substrate: diffuse(1.0) })),
+		("oren-nayar", Arc::new(OrenNayar { albedo: constant(1.0), sigma: constant(0.5) })),
+		("sheen", Arc::new(Sheen { albedo: constant(1.0), roughness: constant(0.5) })),
+		("principled", Arc::new(Principled {
+			base_color: constant(1.0),
+			metallic: constant(0.5),
+			roughness: constant(0.5),
+			specular: constant(0.5),
+			sheen: constant(0.5),
+			clearcoat: constant(0.5),
+			transmission: constant(0.5),
+			ior: 1.5,
+		})),
+	];
+
+	let mut failures = Vec::new();
+	for &spectral in &[false, true] {
+		let suffix = if spectral { " (spectral)" } else { "" };
+		for &(name, ref material, expected) in &cases {
+			let radiance = furnace_radiance(material.clone(), spectral);
+			if (radiance - Vec3::thrice(expected)).map(f32::abs).max_elem() > 0.02 {
+				failures.push(format!("{}{}: radiance {:?} instead of {}", name, suffix, radiance, expected));
+			}
+		}
+		for &(name, ref material) in &lossy {
+			let radiance = furnace_radiance(material.clone(), spectral);
+			if radiance.max_elem() > 1.02 {
+				failures.push(format!("{}{}: radiance {:?} above one", name, suffix, radiance));
+			}
+		}
+	}
+	assert!(failures.is_empty(), "white furnace test failed:\n{}", failures.join("\n"));
+}
+
+/// Path of the reference rendering of the Cornell box, relative to the crate root
+#[cfg(test)]
+const CORNELL_BOX_GOLDEN: &str = "tests/data/cornell_box.golden";
+
+/// Low resolution Cornell box, with a diffuse and a glass sphere
+#[cfg(test)]
+fn cornell_box() -> (Scene, ::camera::Camera) {
+	use std::sync::Arc;
+	use primitive::{Parallelogram, Sphere};
+
+	let diffuse = |albedo: Vec3| -> Arc<Material> { Arc::new(Diffuse { albedo: Texture::Constant(albedo) }) };
+	let white = diffuse(Vec3::thrice(0.75));
+	let wall = |center: Vec3, normal: Vec3, material: &Arc<Material>| Object::Scatterer {
+		surface: Box::new(Parallelogram::from_square(center, normal, 2.0)),
+		material: material.clone(),
+	};
+
+	let objects = vec![
+		wall(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), &white),
+		wall(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), &white),
+		wall(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), &white),
+		// red on the left, green on the right from the camera
+		wall(Vec3::new(1.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), &diffuse(Vec3::new(0.63, 0.065, 0.05))),
+		wall(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), &diffuse(Vec3::new(0.14, 0.45, 0.09))),
+		Object::Scatterer { surface: Box::new(Sphere::new(0.4, Vec3::new(-0.4, -0.6, 0.3))), material: white.clone() },
+		Object::Scatterer {
+			surface: Box::new(Sphere::new(0.35, Vec3::new(0.45, -0.65, -0.2))),
+			material: Arc::new(Dielectric { albedo: Texture::Constant(Vec3::thrice(1.0)), ior: DielectricIOR::Constant(1.5), thin_film: None }),
+		},
+		Object::Emitter(AreaLight {
+			surface: Box::new(Parallelogram::from_square(Vec3::new(0.0, 0.9, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.5)),
+			emission: Vec3::thrice(15.0),
+			profile: None,
+		}),
+	];
+
+	let transform = Mat4::look_at(Vec3::new(0.0, 0.0, -3.5), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0));
+	let camera = ::camera::Camera::new(&transform, (16, 16), 40.0, ::camera::gamma, None, None);
+	(Scene::new(None, objects), camera)
+}
+
+/// Render the Cornell box, returning the mean and the variance of the samples of each pixel
+#[cfg(test)]
+fn render_cornell_box(spp: usize, seed: u32) -> (Vec<Vec3>, Vec<Vec3>) {
+	use rand::{SeedableRng, XorShiftRng};
+
+	let (scene, camera) = cornell_box();
+	let (width, height) = camera.resolution();
+	let mut rng = XorShiftRng::from_seed([seed, 1, 2, 3]);
+	let mut means = Vec::with_capacity(width * height);
+	let mut variances = Vec::with_capacity(width * height);
+	for y in 0..height {
+		for x in 0..width {
+			let mut sum = Vec3::zero();
+			let mut sum_sq = Vec3::zero();
+			for _ in 0..spp {
+				let (ray, differentials) = camera.make_ray_differentials((x, y), rng.gen(), rng.gen());
+				let v = estimate_radiance(&scene, ray, Some(differentials), &mut rng);
+				sum += v;
+				sum_sq += v * v;
+			}
+			let mean = sum / spp as f32;
+			means.push(mean);
+			variances.push((sum_sq / spp as f32 - mean * mean).map(|v| v.max(0.0)));
+		}
+	}
+	(means, variances)
+}
+
+/// Regenerate the reference rendering of the Cornell box, after an intended change of the results
+/// (`cargo test --release generate_cornell_box_golden -- --ignored`)
+#[test]
+#[ignore]
+fn generate_cornell_box_golden() {
+	use std::io::Write;
+
+	let spp = 16384;
+	// a seed distinct from the one of the test, so that both estimates are independent
+	let (means, _) = render_cornell_box(spp, 42);
+	let (width, height) = cornell_box().1.resolution();
+	let path = ::std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(CORNELL_BOX_GOLDEN);
+	let mut f = ::std::fs::File::create(path).unwrap();
+	writeln!(f, "{} {} {}", width, height, spp).unwrap();
+	for m in means {
+		writeln!(f, "{} {} {}", m.x, m.y, m.z).unwrap();
+	}
+}
+
+#[test]
+fn test_cornell_box_golden() {
+	let golden = include_str!("../tests/data/cornell_box.golden");
+	let mut lines = golden.lines();
+	let header: Vec<usize> = lines.next().unwrap().split_whitespace().map(|v| v.parse().unwrap()).collect();
+	let golden_spp = header[2];
+	let golden: Vec<Vec3> = lines.map(|l| {
+		let v: Vec<f32> = l.split_whitespace().map(|v| v.parse().unwrap()).collect();
+		Vec3::new(v[0], v[1], v[2])
+	}).collect();
+
+	let spp = 1024;
+	let (means, variances) = render_cornell_box(spp, 7);
+	assert_eq!(means.len(), golden.len());
+
+	// Difference with the reference of blocks of pixels, in units of its standard error; without
+	// bias, these follow a standard normal distribution. Single pixels do not have enough
+	// samples for the skewed distribution of path contributions to average out.
+	const BLOCK: usize = 4;
+	let (width, height) = cornell_box().1.resolution();
+	let mut scores = Vec::new();
+	for by in 0..height / BLOCK {
+		for bx in 0..width / BLOCK {
+			let (mut mean, mut variance, mut reference) = (Vec3::zero(), Vec3::zero(), Vec3::zero());
+			for y in by * BLOCK..(by + 1) * BLOCK {
+				for x in bx * BLOCK..(bx + 1) * BLOCK {
+					mean += means[y * width + x];
+					variance += variances[y * width + x];
+					reference += golden[y * width + x];
+				}
+			}
+			for &c in &[Axis::X, Axis::Y, Axis::Z] {
+				let std_error = (variance[c] * (1.0 / spp as f32 + 1.0 / golden_spp as f32)).sqrt();
+				if std_error > 0.0 {
+					scores.push((mean[c] - reference[c]) / std_error);
+				} else {
+					assert!((mean[c] - reference[c]).abs() < 1e-4, "constant pixels differ from the reference");
+				}
+			}
+		}
+	}
+	let n = scores.len() as f32;
+	// a bias moves all the pixels in the same direction
+	let avg = scores.iter().sum::<f32>() / n;
+	assert!(avg.abs() * n.sqrt() < 6.0, "rendering is biased: average score {}", avg);
+	let avg_sq = scores.iter().map(|s| s * s).sum::<f32>() / n;
+	assert!(avg_sq < 2.5, "rendering differs from the reference: average squared score {}", avg_sq);
+
+	// and so does the total energy
+	let total = means.iter().fold(Vec3::zero(), |a, &b| a + b);
+	let total_reference = golden.iter().fold(Vec3::zero(), |a, &b| a + b);
+	let relative = ((total - total_reference) / total_reference).map(f32::abs).max_elem();
+	assert!(relative < 0.03, "total radiance differs from the reference by {}%", relative * 100.0);
+}
@@ -10,16 +10,19 @@ extern crate serde_derive;
 
 pub mod camera;
 pub mod geometry;
+pub mod ies;
 pub mod light;
 pub mod material;
 pub mod math;
 pub mod mesh;
 pub mod primitive;
 pub mod scene;
+pub mod spectrum;
 pub mod texture;
 pub mod obj;
 
 mod distribution;
+mod noise;
 mod integrator;
 mod warp;
 mod bvh;
@@ -47,8 +50,8 @@ pub fn render(scene: Scene, camera: Camera, spp: u32) {
 		let mut rng: rand::XorShiftRng = rand::random();
 		for (x, p) in row.iter_mut().enumerate() {
 			for _ in 0..spp {
-				let ray = camera.make_ray((x, y), rng.gen(), rng.gen());
-				let v = estimate_radiance(&scene, ray, &mut rng);
+				let (ray, differentials) = camera.make_ray_differentials((x, y), rng.gen(), rng.gen());
+				let v = estimate_radiance(&scene, ray, Some(differentials), &mut rng);
 				if !v.has_nan() {
 					*p += v;
 				}
@@ -105,8 +108,8 @@ pub fn render_preview_gui(scene: Scene, camera: Camera) {
 		// render a new frame
 		sum_rad.par_chunks_mut(width).enumerate().zip(rngs.par_iter_mut()).for_each(|((y, row), rng)| {
 			for (x, p) in row.iter_mut().enumerate() {
-				let ray = camera.make_ray((x, y), rng.gen(), rng.gen());
-				let v = estimate_radiance(&scene, ray, rng);
+				let (ray, differentials) = camera.make_ray_differentials((x, y), rng.gen(), rng.gen());
+				let v = estimate_radiance(&scene, ray, Some(differentials), rng);
 				if !v.has_nan() {
 					*p += v;
 				}
@@ -142,7 +145,7 @@ pub fn render_preview_gui(scene: Scene, camera: Camera) {
 				Event::MouseButtonDown { x, y, .. } => {
 					let ray = camera.make_ray((x as usize, y as usize), (0.0, 0.0), (0.0, 0.0));
 					match scene.intersect(ray) {
-						Some(Hit::Scatterer(its, _)) => {
+						Some(Hit::Scatterer(its, _, _)) => {
 							println!("restarting with focal distance = {}", its.distance);
 							camera.set_focus_dist(Some(its.distance));
 							for sr in &mut sum_rad {
@@ -191,8 +194,8 @@ pub fn render_preview_file(scene: Scene, camera: Camera) {
 			let mut local_rng = rng.clone();
 			for (x, p) in row.iter_mut().enumerate() {
 				for _ in 0..SPP_STEP {
-					let ray = camera.make_ray((x, y), local_rng.gen(), local_rng.gen());
-					let v = estimate_radiance(&scene, ray, &mut local_rng);
+					let (ray, differentials) = camera.make_ray_differentials((x, y), local_rng.gen(), local_rng.gen());
+					let v = estimate_radiance(&scene, ray, Some(differentials), &mut local_rng);
 					if !v.has_nan() {
 						*p += v;
 					}
@@ -229,8 +232,8 @@ pub fn render_seq(scene: Scene, camera: Camera, spp: u32) {
 	sum_rad.chunks_mut(width).enumerate().for_each(|(y, row)| {
 		for (x, p) in row.iter_mut().enumerate() {
 			for _ in 0..spp {
-				let ray = camera.make_ray((x, y), rng.gen(), rng.gen());
-				let v = estimate_radiance(&scene, ray, &mut rng);
+				let (ray, differentials) = camera.make_ray_differentials((x, y), rng.gen(), rng.gen());
+				let v = estimate_radiance(&scene, ray, Some(differentials), &mut rng);
 				if !v.has_nan() {
 					*p += v;
 				}
@@ -2,6 +2,11 @@ use distribution::Distribution2D;
 use math::*;
 use texture::*;
 use geometry::Surface;
+use primitive::Parallelogram;
+use ies::IesProfile;
+use material::Material;
+use std::sync::Arc;
+use warp::*;
 
 pub struct DirectSample {
 	pub dir: Vec3,
@@ -18,18 +23,28 @@ pub trait Light {
 	fn eval_direct(&self, dir: Vec3) -> Vec3;
 
 	fn sample_direct(&self, p: Vec3, uv: (f32, f32)) -> (Vec3, DirectSample);
-	fn pdf_direct(&self, dir: Vec3, dist: f32) -> f32;
+	fn pdf_direct(&self, p: Vec3, dir: Vec3, dist: f32) -> f32;
+
+	/// Whether the light is described by a Dirac delta and thus cannot be hit by rays
+	fn is_delta(&self) -> bool {
+		false
+	}
 }
 
 pub struct EnvMap {
 	img: Image,
 	dist: Distribution2D,
+	/// Multiplier applied to the radiance of the image
+	scale: f32,
 	transform: Mat4,
 	inv_transform: Mat4,
+	/// Openings (e.g. windows) through which the environment is visible.
+	/// When non-empty, direct sampling is restricted to directions going through them.
+	portals: Vec<Parallelogram>,
 }
 
 impl EnvMap {
-	pub fn from_image(img: Image, transform: &Mat4) -> EnvMap {
+	pub fn from_image(img: Image, transform: &Mat4, scale: f32) -> EnvMap {
 		// Construct a row-major 2d distribution based on texels luminance.
 		// It will be used to importance sample texels according to their contribution.
 		// The sin(theta) factor counteracts the deformation of equi-rectangular mapping.
@@ -44,15 +59,36 @@ impl EnvMap {
 		}
 		let dist = Distribution2D::new(weights, img.width, img.height);
 
-		// TODO: only keep rotation from transform
+		// Only directions are transformed, so only keep the rotation
+		let rotation = transform.rotation();
+
 		EnvMap {
 			img,
 			dist,
-			transform: transform.clone(),
-			inv_transform: transform.inverse(),
+			scale,
+			transform: rotation,
+			inv_transform: rotation.transpose(),
+			portals: Vec::new(),
 		}
 	}
 
+	/// Restrict direct sampling of the environment to the directions going through the given portals
+	///
+	/// Every opening through which the environment lights the scene must be covered by
+	/// a portal; light coming through other directions would be missed by direct sampling.
+	pub fn set_portals(&mut self, portals: Vec<Parallelogram>) {
+		self.portals = portals;
+	}
+
+	/// Solid angle density of sampling `dir` from `p` through a uniformly chosen portal
+	fn portals_pdf(&self, p: Vec3, dir: Vec3) -> f32 {
+		let ray = Ray { origin: p, direction: dir };
+		let pdf: f32 = self.portals.iter()
+			.filter_map(|portal| portal.intersect(ray).map(|its| portal.pdf_direct(dir, its.distance)))
+			.sum();
+		pdf / self.portals.len() as f32
+	}
+
 	fn direction_to_uv(&self, d: Vec3) -> ((f32, f32), f32) {
 		let l = self.inv_transform.transform_vector(d);
 		let u = l.z.atan2(l.x) * INV_2_PI + 0.5;
@@ -61,64 +97,344 @@ impl EnvMap {
 		((u, v), sin_theta)
 	}
 
-	fn uv_to_direction(&self, (u, v): (f32, f32)) -> (Vec3, f32) {
-		let phi = (u - 0.5) * 2.0 * PI;
-		let theta = v * PI;
-		let (sin_theta, cos_theta) = theta.sin_cos();
-		let (sin_phi, cos_phi) = phi.sin_cos();
-		let local_dir = Vec3::new(sin_theta * cos_phi, -cos_theta, sin_theta * sin_phi);
+	fn uv_to_direction(&self, uv: (f32, f32)) -> (Vec3, f32) {
+		let (local_dir, sin_theta) = equirect_direction(uv);
 		let dir = self.transform.transform_vector(local_dir);
 		(dir, sin_theta)
 	}
+
+	/// Create an environment map from the six faces of a cube map, ordered as +X, -X, +Y, -Y, +Z, -Z
+	///
+	/// Faces follow the OpenGL cube map convention and must be square and of the same size.
+	/// They are resampled to an equirectangular image, which is then handled as in `from_image`.
+	pub fn from_cube_faces(faces: &[Image; 6], transform: &Mat4, scale: f32) -> EnvMap {
+		let n = faces[0].width;
+		let img = resample_equirect(4 * n, 2 * n, |d| {
+			let (face, s, t) = cube_face_coords(d);
+			faces[face].eval_clamped((s, t))
+		});
+		EnvMap::from_image(img, transform, scale)
+	}
+
+	/// Create an environment map from a cube map whose faces are laid out in a single image
+	/// as a horizontal (4×3 faces) or vertical (3×4 faces) cross
+	pub fn from_cube_cross(img: Image, transform: &Mat4, scale: f32) -> EnvMap {
+		// position of the +X, -X, +Y, -Y, +Z and -Z faces in the cross, in face units
+		let (n, layout) = if img.width * 3 == img.height * 4 {
+			(img.width / 4, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (3, 1)])
+		} else if img.width * 4 == img.height * 3 {
+			(img.width / 3, [(2, 1), (0, 1), (1, 0), (1, 2), (1, 1), (1, 3)])
+		} else {
+			panic!("cube map cross has invalid dimensions {}×{}", img.width, img.height);
+		};
+
+		let face = |i: usize| {
+			let (x, y) = layout[i];
+			img.crop(x * n, y * n, n, n)
+		};
+		let mut faces = [face(0), face(1), face(2), face(3), face(4), face(5)];
+
+		// in the vertical layout, the -Z face is upside down
+		if img.width < img.height {
+			let f = &faces[5];
+			let mut pixels = (0..n * n).map(|i| f.get(i % n, i / n)).collect::<Vec<Vec3>>();
+			pixels.reverse();
+			faces[5] = Image::from_pixels(n, n, pixels);
+		}
+
+		EnvMap::from_cube_faces(&faces, transform, scale)
+	}
+
+	/// Create an environment map from a square light probe image
+	///
+	/// The probe is seen from a camera looking toward -Z.
+	pub fn from_light_probe(img: Image, mapping: ProbeMapping, transform: &Mat4, scale: f32) -> EnvMap {
+		let img = resample_equirect(2 * img.width, img.width, |d| {
+			let (x, y) = match mapping {
+				ProbeMapping::MirrorBall => {
+					// the image shows the normals of a reflective sphere;
+					// the direction opposite to the camera maps to the rim
+					let n = d + Vec3::new(0.0, 0.0, 1.0);
+					let l = n.length();
+					if l < EPSILON { (1.0, 0.0) } else { (n.x / l, n.y / l) }
+				}
+				ProbeMapping::Angular => {
+					// the distance to the center is proportional to the angle with -Z
+					let r = (d.x * d.x + d.y * d.y).sqrt();
+					let theta = (-d.z).max(-1.0).min(1.0).acos() * INV_PI;
+					if r < EPSILON { (0.0, 0.0) } else { (d.x / r * theta, d.y / r * theta) }
+				}
+			};
+			img.eval_clamped((0.5 * (1.0 + x), 0.5 * (1.0 - y)))
+		});
+		EnvMap::from_image(img, transform, scale)
+	}
+}
+
+/// Parameterization of a light probe image
+#[derive(Copy, Clone, Debug)]
+pub enum ProbeMapping {
+	/// Photograph of a mirror ball
+	MirrorBall,
+	/// Angular map, as popularized by Paul Debevec's light probe gallery
+	Angular,
+}
+
+/// Direction in the local frame of an environment map corresponding to the given parametric
+/// coordinates of its equirectangular image. Also returns sin(theta).
+fn equirect_direction((u, v): (f32, f32)) -> (Vec3, f32) {
+	let phi = (u - 0.5) * 2.0 * PI;
+	let theta = v * PI;
+	let (sin_theta, cos_theta) = theta.sin_cos();
+	let (sin_phi, cos_phi) = phi.sin_cos();
+	(Vec3::new(sin_theta * cos_phi, -cos_theta, sin_theta * sin_phi), sin_theta)
+}
+
+/// Build an equirectangular image by evaluating `f` for the direction of each pixel
+fn resample_equirect<F>(width: usize, height: usize, f: F) -> Image
+	where F: Fn(Vec3) -> Vec3
+{
+	let mut pixels = Vec::with_capacity(width * height);
+	for y in 0..height {
+		for x in 0..width {
+			let u = (x as f32 + 0.5) / width as f32;
+			let v = 1.0 - (y as f32 + 0.5) / height as f32;
+			pixels.push(f(equirect_direction((u, v)).0));
+		}
+	}
+	Image::from_pixels(width, height, pixels)
+}
+
+/// Return the index of the cube map face hit by `d` and image coordinates within that face
+fn cube_face_coords(d: Vec3) -> (usize, f32, f32) {
+	let (ax, ay, az) = (d.x.abs(), d.y.abs(), d.z.abs());
+	let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+		if d.x > 0.0 { (0, -d.z, -d.y, ax) } else { (1, d.z, -d.y, ax) }
+	} else if ay >= az {
+		if d.y > 0.0 { (2, d.x, d.z, ay) } else { (3, d.x, -d.z, ay) }
+	} else {
+		if d.z > 0.0 { (4, d.x, -d.y, az) } else { (5, -d.x, -d.y, az) }
+	};
+	(face, 0.5 * (sc / ma + 1.0), 0.5 * (tc / ma + 1.0))
 }
 
 impl Light for EnvMap {
 	fn eval_direct(&self, dir: Vec3) -> Vec3 {
 		let (uv, _) = self.direction_to_uv(dir);
-		self.img.eval(uv)
+		self.img.eval(uv) * self.scale
 	}
 
-	fn sample_direct(&self, _p: Vec3, (u, v): (f32, f32)) -> (Vec3, DirectSample) {
-		let ((x, y), tex_pdf) = self.dist.sample(u, v);
+	fn sample_direct(&self, p: Vec3, (u, v): (f32, f32)) -> (Vec3, DirectSample) {
+		if !self.portals.is_empty() {
+			// pick a portal uniformly, then a point on it
+			let n = self.portals.len();
+			let i = ((u * n as f32) as usize).min(n - 1);
+			let u = u * n as f32 - i as f32;
+			let dir = self.portals[i].sample_direct(p, (u, v)).dir;
+			let pdf = self.portals_pdf(p, dir);
+			return (self.eval_direct(dir), DirectSample { dir, dist: INFINITY, pdf });
+		}
 
-		let u = (x as f32 + 0.5) / self.img.width  as f32;
-		let v = 1.0 - (y as f32 + 0.5) / self.img.height as f32;
-		let (dir, sin_theta) = self.uv_to_direction((u, v));
+		// sample a position within the image; its row-major coordinates start from the top
+		let ((x, y), uv_pdf) = self.dist.sample(u, v);
+		let uv = (x, 1.0 - y);
+		let (dir, sin_theta) = self.uv_to_direction(uv);
 
-		let img_size = (self.img.width * self.img.height) as f32;
-		let dir_pdf = tex_pdf * img_size / (2.0 * PI * PI * sin_theta);
-		let emission = self.img.eval((u, v));
+		// positions are sampled continuously, possibly right at a pole
+		let dir_pdf = uv_pdf / (2.0 * PI * PI * sin_theta.max(EPSILON));
+		let emission = self.img.eval(uv) * self.scale;
 
 		(emission, DirectSample { dir, dist: INFINITY, pdf: dir_pdf })
 	}
 
-	fn pdf_direct(&self, dir: Vec3, _dist: f32) -> f32 {
+	fn pdf_direct(&self, p: Vec3, dir: Vec3, _dist: f32) -> f32 {
+		if !self.portals.is_empty() {
+			return self.portals_pdf(p, dir);
+		}
+
 		let ((u, v), sin_theta) = self.direction_to_uv(dir);
-		let x = (u * self.img.width  as f32) as usize;
-		let y = ((1.0 - v) * self.img.height as f32) as usize;
+		self.dist.pdf(u, 1.0 - v) / (2.0 * PI * PI * sin_theta.max(EPSILON))
+	}
+}
+
+/// Background of uniform radiance
+pub struct ConstantBackground {
+	pub emission: Vec3,
+}
+
+impl Light for ConstantBackground {
+	fn eval_direct(&self, _dir: Vec3) -> Vec3 {
+		self.emission
+	}
+
+	fn sample_direct(&self, _p: Vec3, uv: (f32, f32)) -> (Vec3, DirectSample) {
+		let dir = uniform_sphere(uv);
+		(self.emission, DirectSample { dir, dist: INFINITY, pdf: uniform_sphere_pdf(dir) })
+	}
+
+	fn pdf_direct(&self, _p: Vec3, dir: Vec3, _dist: f32) -> f32 {
+		uniform_sphere_pdf(dir)
+	}
+}
+
+/// Background blending linearly between two colors from the bottom to the top direction
+pub struct GradientBackground {
+	pub top: Vec3,
+	pub bottom: Vec3,
+	pub up: Vec3,
+}
 
-		let img_size = (self.img.width * self.img.height) as f32;
-		let tex_pdf = self.dist.pdf(x, y);
+impl Light for GradientBackground {
+	fn eval_direct(&self, dir: Vec3) -> Vec3 {
+		let t = 0.5 * (1.0 + Vec3::dot(dir, self.up.normalized()));
+		lerp(self.bottom, self.top, t)
+	}
+
+	fn sample_direct(&self, _p: Vec3, uv: (f32, f32)) -> (Vec3, DirectSample) {
+		let dir = uniform_sphere(uv);
+		(self.eval_direct(dir), DirectSample { dir, dist: INFINITY, pdf: uniform_sphere_pdf(dir) })
+	}
 
-		tex_pdf * img_size / (2.0 * PI * PI * sin_theta)
+	fn pdf_direct(&self, _p: Vec3, dir: Vec3, _dist: f32) -> f32 {
+		uniform_sphere_pdf(dir)
 	}
 }
 
 pub struct AreaLight {
 	pub surface: Box<SampleDirectSurface + Send + Sync>,
 	pub emission: Vec3,
+	/// Optional angular profile modulating the emission
+	pub profile: Option<IesProfile>,
+}
+
+impl AreaLight {
+	fn emission(&self, dir: Vec3) -> Vec3 {
+		match self.profile {
+			Some(ref profile) => self.emission * profile.eval(-dir),
+			None => self.emission,
+		}
+	}
 }
 
 impl Light for AreaLight {
+	fn eval_direct(&self, dir: Vec3) -> Vec3 {
+		self.emission(dir)
+	}
+
+	fn sample_direct(&self, p: Vec3, uv: (f32, f32)) -> (Vec3, DirectSample) {
+		let sample = self.surface.sample_direct(p, uv);
+		(self.emission(sample.dir), sample)
+	}
+
+	fn pdf_direct(&self, _p: Vec3, dir: Vec3, dist: f32) -> f32 {
+		self.surface.pdf_direct(dir, dist)
+	}
+}
+
+/// Surface with an emissive material, sampled as a light while still scattering light
+pub struct EmissiveSurface {
+	pub surface: Box<SampleDirectSurface + Send + Sync>,
+	pub material: Arc<Material>,
+}
+
+impl Light for EmissiveSurface {
+	/// The emission depends on the hit point, so it is evaluated from the material
+	/// when the surface is hit rather than here
 	fn eval_direct(&self, _dir: Vec3) -> Vec3 {
-		self.emission
+		Vec3::zero()
 	}
 
 	fn sample_direct(&self, p: Vec3, uv: (f32, f32)) -> (Vec3, DirectSample) {
-		(self.emission, self.surface.sample_direct(p, uv))
+		let sample = self.surface.sample_direct(p, uv);
+		// find the texture coordinates of the sampled point
+		let emission = match self.surface.intersect(Ray { origin: p, direction: sample.dir }) {
+			Some(its) => {
+				let tc = TexCoords::new(its.uv, p + sample.dir * its.distance);
+				self.material.emission(tc) * self.material.opacity(tc)
+			}
+			None => Vec3::zero(),
+		};
+		(emission, sample)
 	}
 
-	fn pdf_direct(&self, dir: Vec3, dist: f32) -> f32 {
+	fn pdf_direct(&self, _p: Vec3, dir: Vec3, dist: f32) -> f32 {
 		self.surface.pdf_direct(dir, dist)
 	}
 }
+
+/// Spot light cone, pointing toward -Y in the local frame of the light
+pub struct Spot {
+	pub transform: Mat4,
+	/// Half-angle of the cone in degrees
+	pub angle: f32,
+	/// Angular width of the smooth transition at the border of the cone in degrees
+	pub falloff: f32,
+}
+
+/// Infinitesimal light source, optionally restricted to a cone (spot light) and
+/// modulated by an angular profile
+pub struct PointLight {
+	pub position: Vec3,
+	/// Radiant intensity
+	pub intensity: Vec3,
+	pub spot: Option<Spot>,
+	pub profile: Option<IesProfile>,
+}
+
+impl PointLight {
+	/// Intensity emitted toward the direction `dir`
+	pub fn intensity(&self, dir: Vec3) -> Vec3 {
+		let mut intensity = self.intensity;
+		if let Some(ref spot) = self.spot {
+			let axis = spot.transform.transform_vector(Vec3::new(0.0, -1.0, 0.0)).normalized();
+			let cos_theta = Vec3::dot(axis, dir);
+			let cos_total = (spot.angle * PI / 180.0).cos();
+			let cos_start = ((spot.angle - spot.falloff).max(0.0) * PI / 180.0).cos();
+			if cos_theta <= cos_total {
+				return Vec3::zero();
+			}
+			if cos_theta < cos_start {
+				let t = (cos_theta - cos_total) / (cos_start - cos_total);
+				intensity = intensity * (t * t * (3.0 - 2.0 * t));
+			}
+		}
+		if let Some(ref profile) = self.profile {
+			intensity = intensity * profile.eval(dir);
+		}
+		intensity
+	}
+
+	/// Sample an emission direction, e.g. to trace paths starting from the light
+	///
+	/// Returns the direction, the intensity emitted along it and its solid angle density.
+	pub fn sample_emission(&self, uv: (f32, f32)) -> (Vec3, Vec3, f32) {
+		let (dir, pdf) = match self.profile {
+			Some(ref profile) => profile.sample(uv),
+			None => {
+				let dir = uniform_sphere(uv);
+				(dir, uniform_sphere_pdf(dir))
+			}
+		};
+		(dir, self.intensity(dir), pdf)
+	}
+}
+
+impl Light for PointLight {
+	fn eval_direct(&self, _dir: Vec3) -> Vec3 {
+		Vec3::zero()
+	}
+
+	fn sample_direct(&self, p: Vec3, _uv: (f32, f32)) -> (Vec3, DirectSample) {
+		let (dir, dist) = Vec3::dir_and_dist(p, self.position);
+		let emission = self.intensity(-dir) / (dist * dist);
+		(emission, DirectSample { dir, dist, pdf: 1.0 })
+	}
+
+	fn pdf_direct(&self, _p: Vec3, _dir: Vec3, _dist: f32) -> f32 {
+		0.0
+	}
+
+	fn is_delta(&self) -> bool {
+		true
+	}
+}
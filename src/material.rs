@@ -1,7 +1,12 @@
-use texture::Texture;
+use texture::{Texture, TexCoords};
 use math::*;
 use warp::*;
-use std::sync::Arc;
+use distribution::Distribution1D;
+use spectrum;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 
 pub struct BSDFSample {
 	pub direction: Vec3,
@@ -21,33 +26,170 @@ const NULL_SAMPLE: BSDFSample = BSDFSample {
 };
 
 pub trait Material: Sync + Send {
-	fn sample(&self, _dir_in: Vec3, _uv: (f32, f32), _rnd: Vec3) -> BSDFSample;
+	fn sample(&self, _dir_in: Vec3, _tc: TexCoords, _rnd: Vec3) -> BSDFSample;
 
-	fn eval(&self, _dir_in: Vec3, _dir_out: Vec3, _uv: (f32, f32)) -> Vec3 {
+	fn eval(&self, _dir_in: Vec3, _dir_out: Vec3, _tc: TexCoords) -> Vec3 {
 		Vec3::zero()
 	}
 
-	fn pdf(&self, _dir_in: Vec3, _dir_out: Vec3, _uv: (f32, f32)) -> f32 {
+	fn pdf(&self, _dir_in: Vec3, _dir_out: Vec3, _tc: TexCoords) -> f32 {
 		0.0
 	}
 
 	fn is_purely_specular(&self) -> bool {
 		true
 	}
+
+	/// Probability for a ray to interact with the surface rather than to go straight through it
+	fn opacity(&self, _tc: TexCoords) -> f32 {
+		1.0
+	}
+
+	/// Radiance emitted by the surface, on both sides
+	fn emission(&self, _tc: TexCoords) -> Vec3 {
+		Vec3::zero()
+	}
+
+	/// Whether the surface scatters any of the light it receives, unlike null or black materials
+	fn scatters_light(&self) -> bool {
+		true
+	}
+
+	/// Whether light is scattered differently depending on its wavelength
+	///
+	/// Spectral rendering then continues paths with their hero wavelength only.
+	fn is_dispersive(&self) -> bool {
+		false
+	}
+
+	/// Whether the material scatters light in the same way on both sides of the surface
+	///
+	/// Otherwise, the back side usually reflects nothing.
+	fn is_two_sided(&self) -> bool {
+		false
+	}
+
+	/// Shading normal in the local frame of the surface, when perturbed by the material
+	/// (e.g. by a normal map)
+	///
+	/// Directions given to the other methods are then expressed in the frame of this normal.
+	fn shading_normal(&self, _tc: TexCoords) -> Option<Vec3> {
+		None
+	}
+
+	/// Same as `sample`, for light of wavelength `lambda` (in nanometers)
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, _lambda: f32) -> BSDFSample {
+		self.sample(dir_in, tc, rnd)
+	}
+
+	/// Same as `eval`, for light of wavelength `lambda` (in nanometers)
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, _lambda: f32) -> Vec3 {
+		self.eval(dir_in, dir_out, tc)
+	}
+
+	/// Same as `pdf`, for light of wavelength `lambda` (in nanometers)
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, _lambda: f32) -> f32 {
+		self.pdf(dir_in, dir_out, tc)
+	}
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct ComplexIOR {
 	pub eta: Vec3,
 	pub k: Vec3,
 }
 
+impl ComplexIOR {
+	/// Tabulated index of refraction of a conductor, given by its chemical symbol
+	pub fn from_symbol(symbol: &str) -> Option<ComplexIOR> {
+		CONDUCTORS_IOR.iter().find(|t| t.0 == symbol).map(|t| t.1)
+	}
+
+	/// Load a measured spectral index of refraction (see `spectrum::load_nk`) and convert it to RGB
+	pub fn load<P: AsRef<Path>>(path: P) -> ComplexIOR {
+		let (eta, k) = spectrum::load_nk(path);
+		ComplexIOR { eta: eta.to_rgb(), k: k.to_rgb() }
+	}
+}
+
+/// Index of refraction of a dielectric, possibly varying with the wavelength
+///
+/// Dispersion formulas take wavelengths in micrometers.
+#[derive(Clone, Copy)]
+pub enum DielectricIOR {
+	Constant(f32),
+	/// n = A + B / λ²
+	Cauchy(f32, f32),
+	/// n² = 1 + Σ Bᵢ λ² / (λ² - Cᵢ)
+	Sellmeier([f32; 3], [f32; 3]),
+}
+
+impl DielectricIOR {
+	/// Index of refraction at the wavelength `lambda` (in nanometers)
+	pub fn eval(&self, lambda: f32) -> f32 {
+		let l2 = (lambda * 1e-3).powi(2);
+		match *self {
+			DielectricIOR::Constant(ior) => ior,
+			DielectricIOR::Cauchy(a, b) => a + b / l2,
+			DielectricIOR::Sellmeier(b, c) => {
+				(1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).max(1.0).sqrt()
+			}
+		}
+	}
+
+	pub fn is_dispersive(&self) -> bool {
+		match *self {
+			DielectricIOR::Constant(_) => false,
+			_ => true,
+		}
+	}
+}
+
+/// Thin dielectric film coating a surface, whose interferences make it iridescent
+pub struct ThinFilm {
+	/// Thickness of the film in nanometers
+	pub thickness: Texture,
+	pub ior: f32,
+}
+
+impl ThinFilm {
+	/// Reflectance of the coated interface between a medium of IOR `outer_ior` and the substrate
+	fn reflectance(&self, tc: TexCoords, outer_ior: f32, substrate: ComplexIOR, cos_i: f32) -> Vec3 {
+		let thickness = self.thickness.eval(tc).avg().max(0.0);
+		fresnel::thin_film_coating_rgb(outer_ior, self.ior, substrate, cos_i, thickness)
+	}
+}
+
+/// Roughness of a microfacet material, possibly different along the u and v directions
+pub enum Roughness {
+	Isotropic(Texture),
+	Anisotropic(Texture, Texture),
+}
+
+impl Roughness {
+	/// Return the roughness along the u and v directions
+	pub fn eval(&self, tc: TexCoords) -> (f32, f32) {
+		match *self {
+			Roughness::Isotropic(ref t) => {
+				let alpha = t.eval(tc).avg().max(MIN_ALPHA);
+				(alpha, alpha)
+			}
+			Roughness::Anisotropic(ref tu, ref tv) => {
+				(tu.eval(tc).avg().max(MIN_ALPHA), tv.eval(tc).avg().max(MIN_ALPHA))
+			}
+		}
+	}
+}
+
+/// Smallest microfacet roughness; a perfectly smooth distribution is degenerate
+const MIN_ALPHA: f32 = 1e-4;
+
 pub struct Diffuse {
 	pub albedo: Texture,
 }
 
 impl Material for Diffuse {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
 		if -cos_theta(dir_in) <= 0.0 {
 			return NULL_SAMPLE;
 		}
@@ -57,16 +199,126 @@ impl Material for Diffuse {
 		BSDFSample {
 			direction: d,
 			pdf: cosine_hemisphere_pdf(d),
-			weight: self.albedo.eval(uv),
+			weight: self.albedo.eval(tc),
+			is_specular: false,
+		}
+	}
+
+	fn eval(&self, _dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.albedo.eval(tc) * (INV_PI * cos_theta(dir_out).max(0.0))
+	}
+
+	fn pdf(&self, _dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> f32 {
+		INV_PI * cos_theta(dir_out).max(0.0)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		false
+	}
+
+	fn scatters_light(&self) -> bool {
+		!self.albedo.is_black()
+	}
+}
+
+/// Rough diffuse reflection of Oren and Nayar, with the standard deviation of
+/// the facets slope angle (in radians) given by `sigma`
+pub struct OrenNayar {
+	pub albedo: Texture,
+	pub sigma: Texture,
+}
+
+impl Material for OrenNayar {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		if -cos_theta(dir_in) <= 0.0 {
+			return NULL_SAMPLE;
+		}
+
+		let d = cosine_hemisphere((rnd.x, rnd.y));
+		let pdf = cosine_hemisphere_pdf(d);
+
+		BSDFSample {
+			direction: d,
+			pdf,
+			weight: self.eval(dir_in, d, tc) / pdf,
+			is_specular: false,
+		}
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return Vec3::zero();
+		}
+
+		let sigma2 = self.sigma.eval(tc).avg().powi(2);
+		let a = 1.0 - 0.5 * sigma2 / (sigma2 + 0.33);
+		let b = 0.45 * sigma2 / (sigma2 + 0.09);
+
+		// max(0, cos(phi_i - phi_o)) * sin(theta_max) * tan(theta_min)
+		let s = (-dir_in.x * dir_out.x - dir_in.z * dir_out.z).max(0.0) / cos_i.max(cos_o);
+
+		self.albedo.eval(tc) * (INV_PI * (a + b * s) * cos_o)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> f32 {
+		if -cos_theta(dir_in) <= 0.0 {
+			return 0.0;
+		}
+		INV_PI * cos_theta(dir_out).max(0.0)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		false
+	}
+}
+
+/// Retro-reflective sheen of cloth and velvet, using the "Charlie" microfiber
+/// distribution of Estevez and Kulla with Neubelt's visibility term
+pub struct Sheen {
+	pub albedo: Texture,
+	pub roughness: Texture,
+}
+
+impl Material for Sheen {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		if -cos_theta(dir_in) <= 0.0 {
+			return NULL_SAMPLE;
+		}
+
+		let d = cosine_hemisphere((rnd.x, rnd.y));
+		let pdf = cosine_hemisphere_pdf(d);
+
+		BSDFSample {
+			direction: d,
+			pdf,
+			weight: self.eval(dir_in, d, tc) / pdf,
 			is_specular: false,
 		}
 	}
 
-	fn eval(&self, _dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
-		self.albedo.eval(uv) * (INV_PI * cos_theta(dir_out).max(0.0))
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return Vec3::zero();
+		}
+
+		let h = (dir_out - dir_in).normalized();
+		let sin2_h = (1.0 - h.y * h.y).max(0.0);
+
+		let inv_r = 1.0 / self.roughness.eval(tc).avg().max(MIN_ALPHA);
+		let d = (2.0 + inv_r) * sin2_h.powf(0.5 * inv_r) * (0.5 * INV_PI);
+		let v = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+
+		self.albedo.eval(tc) * (d * v * cos_o)
 	}
 
-	fn pdf(&self, _dir_in: Vec3, dir_out: Vec3, _uv: (f32, f32)) -> f32 {
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> f32 {
+		if -cos_theta(dir_in) <= 0.0 {
+			return 0.0;
+		}
 		INV_PI * cos_theta(dir_out).max(0.0)
 	}
 
@@ -75,12 +327,406 @@ impl Material for Diffuse {
 	}
 }
 
+/// Invisible material that lets light go through unaffected
+pub struct Null;
+
+impl Material for Null {
+	fn sample(&self, _dir_in: Vec3, _tc: TexCoords, _rnd: Vec3) -> BSDFSample {
+		NULL_SAMPLE
+	}
+
+	fn opacity(&self, _tc: TexCoords) -> f32 {
+		0.0
+	}
+
+	fn scatters_light(&self) -> bool {
+		false
+	}
+}
+
+/// Make a material partially transparent according to an opacity texture (e.g. for alpha cutouts)
+pub struct Transparency {
+	pub opacity: Texture,
+	pub base: Arc<Material>,
+}
+
+impl Material for Transparency {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.base.sample(dir_in, tc, rnd)
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.base.eval(dir_in, dir_out, tc)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.base.pdf(dir_in, dir_out, tc)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		self.base.is_purely_specular()
+	}
+
+	fn opacity(&self, tc: TexCoords) -> f32 {
+		self.opacity.eval(tc).avg().min(1.0).max(0.0) * self.base.opacity(tc)
+	}
+
+	fn emission(&self, tc: TexCoords) -> Vec3 {
+		self.base.emission(tc)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.base.is_dispersive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn shading_normal(&self, tc: TexCoords) -> Option<Vec3> {
+		self.base.shading_normal(tc)
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		self.base.sample_wavelength(dir_in, tc, rnd, lambda)
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		self.base.eval_wavelength(dir_in, dir_out, tc, lambda)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		self.base.pdf_wavelength(dir_in, dir_out, tc, lambda)
+	}
+}
+
+/// Make a material glow, e.g. for displays or lava, while still scattering light
+pub struct Emissive {
+	pub emission: Texture,
+	pub base: Arc<Material>,
+}
+
+impl Material for Emissive {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.base.sample(dir_in, tc, rnd)
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.base.eval(dir_in, dir_out, tc)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.base.pdf(dir_in, dir_out, tc)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		self.base.is_purely_specular()
+	}
+
+	fn scatters_light(&self) -> bool {
+		self.base.scatters_light()
+	}
+
+	fn opacity(&self, tc: TexCoords) -> f32 {
+		self.base.opacity(tc)
+	}
+
+	fn emission(&self, tc: TexCoords) -> Vec3 {
+		self.emission.eval(tc) + self.base.emission(tc)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.base.is_dispersive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn shading_normal(&self, tc: TexCoords) -> Option<Vec3> {
+		self.base.shading_normal(tc)
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		self.base.sample_wavelength(dir_in, tc, rnd, lambda)
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		self.base.eval_wavelength(dir_in, dir_out, tc, lambda)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		self.base.pdf_wavelength(dir_in, dir_out, tc, lambda)
+	}
+}
+
+/// Make a material look the same from both sides, e.g. for single-sided planes of imported scenes
+///
+/// Hits on the back side are handled as front side ones, in a frame rotated by half a turn
+/// around the tangent (which keeps the anisotropy of the base material).
+pub struct TwoSided {
+	pub base: Arc<Material>,
+}
+
+/// Rotate a local direction by half a turn around the tangent, if `back` is set
+fn flip_side(dir: Vec3, back: bool) -> Vec3 {
+	if back { Vec3::new(dir.x, -dir.y, -dir.z) } else { dir }
+}
+
+impl Material for TwoSided {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.sample_wavelength(dir_in, tc, rnd, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.eval_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.pdf_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		self.base.is_purely_specular()
+	}
+
+	fn scatters_light(&self) -> bool {
+		self.base.scatters_light()
+	}
+
+	fn opacity(&self, tc: TexCoords) -> f32 {
+		self.base.opacity(tc)
+	}
+
+	fn emission(&self, tc: TexCoords) -> Vec3 {
+		self.base.emission(tc)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.base.is_dispersive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		true
+	}
+
+	fn shading_normal(&self, tc: TexCoords) -> Option<Vec3> {
+		self.base.shading_normal(tc)
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		let back = cos_theta(dir_in) > 0.0;
+		let mut sample = self.base.sample_wavelength(flip_side(dir_in, back), tc, rnd, lambda);
+		sample.direction = flip_side(sample.direction, back);
+		sample
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		let back = cos_theta(dir_in) > 0.0;
+		self.base.eval_wavelength(flip_side(dir_in, back), flip_side(dir_out, back), tc, lambda)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		let back = cos_theta(dir_in) > 0.0;
+		self.base.pdf_wavelength(flip_side(dir_in, back), flip_side(dir_out, back), tc, lambda)
+	}
+}
+
+/// Perturbation of the shading normal of a surface
+pub enum NormalMap {
+	/// Tangent space normal map, with the red, green and blue channels along the
+	/// directions of increasing u, increasing v and the normal
+	Tangent(Texture),
+	/// Height field (average of the channels) scaled by a strength factor, in texture space
+	Bump(Texture, f32),
+}
+
+/// Change the shading normal of a material to add small-scale details
+pub struct NormalMapped {
+	pub normal_map: NormalMap,
+	pub base: Arc<Material>,
+}
+
+impl Material for NormalMapped {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.base.sample(dir_in, tc, rnd)
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.base.eval(dir_in, dir_out, tc)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.base.pdf(dir_in, dir_out, tc)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		self.base.is_purely_specular()
+	}
+
+	fn scatters_light(&self) -> bool {
+		self.base.scatters_light()
+	}
+
+	fn opacity(&self, tc: TexCoords) -> f32 {
+		self.base.opacity(tc)
+	}
+
+	fn emission(&self, tc: TexCoords) -> Vec3 {
+		self.base.emission(tc)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.base.is_dispersive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn shading_normal(&self, tc: TexCoords) -> Option<Vec3> {
+		let n = match self.normal_map {
+			NormalMap::Tangent(ref map) => {
+				let c = map.eval(tc) * 2.0 - Vec3::thrice(1.0);
+				// the local frame is (tangent, normal, bitangent)
+				Vec3::new(c.x, c.z, c.y)
+			}
+			NormalMap::Bump(ref height, strength) => {
+				// finite differences over about a texel
+				let (du, dv) = match *height {
+					Texture::Bitmap(ref img) => (1.0 / img.width as f32, 1.0 / img.height as f32),
+					_ => (1e-3, 1e-3),
+				};
+				let h = height.eval(tc).avg();
+				let dh_du = (height.eval(TexCoords { uv: (tc.uv.0 + du, tc.uv.1), ..tc }).avg() - h) / du;
+				let dh_dv = (height.eval(TexCoords { uv: (tc.uv.0, tc.uv.1 + dv), ..tc }).avg() - h) / dv;
+				Vec3::new(-strength * dh_du, 1.0, -strength * dh_dv)
+			}
+		};
+		// ignore normals at grazing angles or below the surface
+		if n.y > 1e-3 {
+			Some(n.normalized())
+		} else {
+			None
+		}
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		self.base.sample_wavelength(dir_in, tc, rnd, lambda)
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		self.base.eval_wavelength(dir_in, dir_out, tc, lambda)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		self.base.pdf_wavelength(dir_in, dir_out, tc, lambda)
+	}
+}
+
+/// Blend of two materials, the weight texture giving the proportion of the first one
+pub struct Mix {
+	pub weight: Texture,
+	pub first: Arc<Material>,
+	pub second: Arc<Material>,
+}
+
+impl Mix {
+	/// Probability of interacting with the first material, given that the surface is not passed through
+	fn first_prob(&self, tc: TexCoords) -> f32 {
+		let w = self.weight.eval(tc).avg().min(1.0).max(0.0);
+		let o1 = w * self.first.opacity(tc);
+		let o2 = (1.0 - w) * self.second.opacity(tc);
+		if o1 + o2 > 0.0 { o1 / (o1 + o2) } else { w }
+	}
+}
+
+impl Material for Mix {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.sample_wavelength(dir_in, tc, rnd, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.eval_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.pdf_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		self.first.is_purely_specular() && self.second.is_purely_specular()
+	}
+
+	fn opacity(&self, tc: TexCoords) -> f32 {
+		let w = self.weight.eval(tc).avg().min(1.0).max(0.0);
+		w * self.first.opacity(tc) + (1.0 - w) * self.second.opacity(tc)
+	}
+
+	fn emission(&self, tc: TexCoords) -> Vec3 {
+		let w = self.weight.eval(tc).avg().min(1.0).max(0.0);
+		w * self.first.emission(tc) + (1.0 - w) * self.second.emission(tc)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.first.is_dispersive() || self.second.is_dispersive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.first.is_two_sided() || self.second.is_two_sided()
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		let p = self.first_prob(tc);
+
+		let (sample, prob) = if rnd.z < p {
+			let rnd = Vec3::new(rnd.x, rnd.y, rnd.z / p);
+			(self.first.sample_wavelength(dir_in, tc, rnd, lambda), p)
+		} else {
+			let rnd = Vec3::new(rnd.x, rnd.y, (rnd.z - p) / (1.0 - p));
+			(self.second.sample_wavelength(dir_in, tc, rnd, lambda), 1.0 - p)
+		};
+
+		if sample.pdf == 0.0 {
+			return NULL_SAMPLE;
+		}
+
+		if sample.is_specular {
+			// the other material has a zero density for a specular direction
+			return BSDFSample { pdf: sample.pdf * prob, ..sample };
+		}
+
+		let pdf = self.pdf_wavelength(dir_in, sample.direction, tc, lambda);
+		if pdf == 0.0 {
+			return NULL_SAMPLE;
+		}
+		BSDFSample {
+			direction: sample.direction,
+			pdf,
+			weight: self.eval_wavelength(dir_in, sample.direction, tc, lambda) / pdf,
+			is_specular: false,
+		}
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		let p = self.first_prob(tc);
+		p * self.first.eval_wavelength(dir_in, dir_out, tc, lambda)
+			+ (1.0 - p) * self.second.eval_wavelength(dir_in, dir_out, tc, lambda)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		let p = self.first_prob(tc);
+		p * self.first.pdf_wavelength(dir_in, dir_out, tc, lambda)
+			+ (1.0 - p) * self.second.pdf_wavelength(dir_in, dir_out, tc, lambda)
+	}
+}
+
 pub struct Mirror {
 	pub albedo: Texture,
 }
 
 impl Material for Mirror {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), _rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, _rnd: Vec3) -> BSDFSample {
 		if -cos_theta(dir_in) <= 0.0 {
 			return NULL_SAMPLE;
 		}
@@ -88,7 +734,7 @@ impl Material for Mirror {
 		BSDFSample {
 			direction: reflect(dir_in),
 			pdf: 1.0,
-			weight: self.albedo.eval(uv),
+			weight: self.albedo.eval(tc),
 			is_specular: true,
 		}
 	}
@@ -96,25 +742,113 @@ impl Material for Mirror {
 
 pub struct Dielectric {
 	pub albedo: Texture,
-	pub ior: f32,
+	pub ior: DielectricIOR,
+	pub thin_film: Option<ThinFilm>,
 }
 
 impl Material for Dielectric {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
-		let eta = if cos_theta(dir_in) >= 0.0 { self.ior } else { 1.0 / self.ior };
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.sample_wavelength(dir_in, tc, rnd, spectrum::REFERENCE_WAVELENGTH)
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.ior.is_dispersive()
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		let ior = self.ior.eval(lambda);
+		let inside = cos_theta(dir_in) >= 0.0;
+		let eta = if inside { ior } else { 1.0 / ior };
 		let cos_i = cos_theta(dir_in).abs();
 		let (reflectance, cos_t) = fresnel::dielectric_reflectance(eta, cos_i);
 
-		let (direction, pdf) = if rnd.z < reflectance {
-			(reflect(dir_in), reflectance)
+		let (direction, pdf, weight) = match self.thin_film {
+			Some(ref film) if reflectance < 1.0 => {
+				let (outer, substrate) = if inside { (ior, 1.0) } else { (1.0, ior) };
+				let substrate = ComplexIOR { eta: Vec3::thrice(substrate), k: Vec3::zero() };
+				let r = film.reflectance(tc, outer, substrate, cos_i);
+				let reflect_prob = r.avg();
+				if rnd.z < reflect_prob {
+					(reflect(dir_in), reflect_prob, r / reflect_prob)
+				} else {
+					(refract(dir_in, eta, cos_t), 1.0 - reflect_prob, (Vec3::thrice(1.0) - r) / (1.0 - reflect_prob))
+				}
+			}
+			_ => {
+				if rnd.z < reflectance {
+					(reflect(dir_in), reflectance, Vec3::thrice(1.0))
+				} else {
+					(refract(dir_in, eta, cos_t), 1.0 - reflectance, Vec3::thrice(1.0))
+				}
+			}
+		};
+
+		BSDFSample {
+			direction,
+			pdf,
+			weight: weight * self.albedo.eval(tc),
+			is_specular: true,
+		}
+	}
+}
+
+/// Infinitely thin dielectric sheet, e.g. a window pane or a soap bubble
+///
+/// Light is either reflected or transmitted straight through, after any number of
+/// reflections inside the sheet.
+pub struct ThinDielectric {
+	pub albedo: Texture,
+	pub ior: f32,
+	/// Absorption coefficient of the sheet multiplied by its thickness
+	pub scaled_sigma_a: Vec3,
+	/// Thickness of the sheet in nanometers, when accounting for thin-film interferences
+	pub film_thickness: Option<f32>,
+}
+
+impl ThinDielectric {
+	/// Return the reflectance and transmittance of the sheet
+	fn reflectance(&self, cos_i: f32) -> (Vec3, Vec3) {
+		let eta = 1.0 / self.ior;
+		let (f, cos_t) = fresnel::dielectric_reflectance(eta, cos_i);
+		let absorption = if self.scaled_sigma_a.max_elem() > 0.0 && cos_t > 0.0 {
+			(self.scaled_sigma_a * (-1.0 / cos_t)).map(f32::exp)
+		} else {
+			Vec3::thrice(1.0)
+		};
+
+		match self.film_thickness {
+			Some(thickness) => {
+				let r = fresnel::thin_film_reflectance_rgb(self.ior, cos_i, thickness);
+				(r, (Vec3::thrice(1.0) - r) * absorption)
+			}
+			None => {
+				// sum the contributions of the light bouncing inside the sheet
+				let t2 = absorption * absorption;
+				let denom = Vec3::thrice(1.0) - t2 * (f * f);
+				let r = Vec3::thrice(f) + t2 * ((1.0 - f) * (1.0 - f) * f) / denom;
+				let t = absorption * ((1.0 - f) * (1.0 - f)) / denom;
+				(r, t)
+			}
+		}
+	}
+}
+
+impl Material for ThinDielectric {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		let cos_i = cos_theta(dir_in).abs();
+		let (r, t) = self.reflectance(cos_i);
+
+		let reflect_prob = r.avg() / (r.avg() + t.avg());
+		let (direction, pdf, weight) = if rnd.z < reflect_prob {
+			(reflect(dir_in), reflect_prob, r / reflect_prob)
 		} else {
-			(refract(dir_in, eta, cos_t), 1.0 - reflectance)
+			(dir_in, 1.0 - reflect_prob, t / (1.0 - reflect_prob))
 		};
 
 		BSDFSample {
 			direction,
 			pdf,
-			weight: self.albedo.eval(uv),
+			weight: weight * self.albedo.eval(tc),
 			is_specular: true,
 		}
 	}
@@ -124,122 +858,260 @@ pub struct RoughDielectric {
 	/// Material albedo; should be set to `1.0` for physical accuracy
 	pub albedo: Texture,
 	/// Index of Refraction
-	pub ior: f32,
-	pub roughness: Texture,
+	pub ior: DielectricIOR,
+	pub roughness: Roughness,
+}
+
+/// Energy compensation of a rough dielectric interface
+struct DielectricInterface {
+	/// Average Fresnel reflectance, from outside and inside the material
+	avg_fresnel: (f32, f32),
+	/// Albedo of the interface, from outside and inside the material
+	albedo: (multiple_scattering::AlbedoTable, multiple_scattering::AlbedoTable),
+}
+
+impl RoughDielectric {
+	/// Energy compensation of the interface, computed for the index of refraction at the
+	/// reference wavelength
+	fn interface(&self) -> &'static DielectricInterface {
+		static INTERFACES: multiple_scattering::Cache<f32, DielectricInterface> = multiple_scattering::Cache::new();
+		let ior = self.ior.eval(spectrum::REFERENCE_WAVELENGTH);
+		INTERFACES.get(ior, || {
+			let outside = multiple_scattering::AlbedoTable::compute(|alpha, dir_in, rnd| {
+				rough_dielectric::sample(ior, alpha, dir_in, rnd).weight.x
+			});
+			let inside = multiple_scattering::AlbedoTable::compute(|alpha, dir_in, rnd| {
+				let dir_in = Vec3::new(dir_in.x, -dir_in.y, dir_in.z);
+				rough_dielectric::sample(ior, alpha, dir_in, rnd).weight.x
+			});
+			DielectricInterface {
+				avg_fresnel: (fresnel::dielectric_average(1.0 / ior), fresnel::dielectric_average(ior)),
+				albedo: (outside, inside),
+			}
+		})
+	}
+
+	/// Tables for the side of the incoming direction and the other one, and the average
+	/// reflectance from the side of the incoming direction
+	fn sides(&self, cos_i: f32) -> (&multiple_scattering::AlbedoTable, &multiple_scattering::AlbedoTable, f32) {
+		let interface = self.interface();
+		let (ref outside, ref inside) = interface.albedo;
+		if cos_i > 0.0 {
+			(outside, inside, interface.avg_fresnel.0)
+		} else {
+			(inside, outside, interface.avg_fresnel.1)
+		}
+	}
+
+	/// Lobe of light scattered several times between microfacets, split between reflection and
+	/// refraction according to the average Fresnel reflectance
+	fn eval_multiple_scattering(&self, alpha: f32, cos_i: f32, cos_o: f32) -> f32 {
+		let (same_side, other_side, avg_fresnel) = self.sides(cos_i);
+		let escaped = 1.0 - same_side.albedo(cos_i, alpha);
+		if cos_i * cos_o > 0.0 {
+			let e_avg = same_side.average(alpha);
+			avg_fresnel * escaped * (1.0 - same_side.albedo(cos_o, alpha)) * cos_o.abs() / (PI * (1.0 - e_avg))
+		} else {
+			let e_avg = other_side.average(alpha);
+			(1.0 - avg_fresnel) * escaped * (1.0 - other_side.albedo(cos_o, alpha)) * cos_o.abs() / (PI * (1.0 - e_avg))
+		}
+	}
 }
 
 impl Material for RoughDielectric {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
-		let eta = if cos_theta(dir_in) >= 0.0 { self.ior } else { 1.0 / self.ior };
-		let cos_i = cos_theta(dir_in).abs();
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		self.sample_wavelength(dir_in, tc, rnd, spectrum::REFERENCE_WAVELENGTH)
+	}
 
-		let roughness = self.roughness.eval(uv).avg();
-		let sample_roughness = (1.2 - 0.2 * cos_i.sqrt()) * roughness;
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.eval_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
 
-		let (h, ggx_pdf) = microfacet::sample(sample_roughness, (rnd.x, rnd.y));
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.pdf_wavelength(dir_in, dir_out, tc, spectrum::REFERENCE_WAVELENGTH)
+	}
 
-		if ggx_pdf < 1e-10 {
+	fn is_purely_specular(&self) -> bool {
+		false
+	}
+
+	fn is_dispersive(&self) -> bool {
+		self.ior.is_dispersive()
+	}
+
+	fn sample_wavelength(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3, lambda: f32) -> BSDFSample {
+		let cos_i = -cos_theta(dir_in);
+		if cos_i == 0.0 {
 			return NULL_SAMPLE;
 		}
 
-		let in_dot_h = -Vec3::dot(dir_in, h);
-		let eta_h = if in_dot_h < 0.0 { self.ior } else { 1.0 / self.ior };
-		let (f, cos_t) = fresnel::dielectric_reflectance(eta_h, in_dot_h.abs());
+		let roughness = self.roughness.eval(tc);
+		let alpha = multiple_scattering::table_alpha(roughness);
+		let (same_side, _, avg_fresnel) = self.sides(cos_i);
+		let ms_prob = 1.0 - same_side.albedo(cos_i, alpha);
+
+		let direction = if rnd.z < ms_prob {
+			let d = cosine_hemisphere((rnd.x, rnd.y));
+			let is_reflection = rnd.z < ms_prob * avg_fresnel;
+			if is_reflection == (cos_i > 0.0) { d } else { Vec3::new(d.x, -d.y, d.z) }
+		} else {
+			let rnd_z = (rnd.z - ms_prob) / (1.0 - ms_prob);
+			let sample = rough_dielectric::sample(self.ior.eval(lambda), roughness, dir_in, Vec3::new(rnd.x, rnd.y, rnd_z));
+			if sample.weight == Vec3::zero() {
+				return NULL_SAMPLE;
+			}
+			sample.direction
+		};
+
+		let pdf = self.pdf_wavelength(dir_in, direction, tc, lambda);
+		if pdf <= 0.0 {
+			return NULL_SAMPLE;
+		}
+
+		BSDFSample {
+			direction,
+			pdf,
+			weight: self.eval_wavelength(dir_in, direction, tc, lambda) / pdf,
+			is_specular: false,
+		}
+	}
+
+	fn eval_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> Vec3 {
+		let roughness = self.roughness.eval(tc);
+		let alpha = multiple_scattering::table_alpha(roughness);
+		let single = rough_dielectric::eval(self.ior.eval(lambda), roughness, dir_in, dir_out);
+		let multiple = self.eval_multiple_scattering(alpha, -cos_theta(dir_in), cos_theta(dir_out));
+		self.albedo.eval(tc) * (single + multiple)
+	}
+
+	fn pdf_wavelength(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords, lambda: f32) -> f32 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i == 0.0 {
+			return 0.0;
+		}
+
+		let roughness = self.roughness.eval(tc);
+		let alpha = multiple_scattering::table_alpha(roughness);
+		let (same_side, _, avg_fresnel) = self.sides(cos_i);
+		let ms_prob = 1.0 - same_side.albedo(cos_i, alpha);
+
+		let single_pdf = rough_dielectric::pdf(self.ior.eval(lambda), roughness, dir_in, dir_out);
+		let side_prob = if cos_i * cos_o > 0.0 { avg_fresnel } else { 1.0 - avg_fresnel };
+		let ms_pdf = side_prob * cos_o.abs() * INV_PI;
+		single_pdf * (1.0 - ms_prob) + ms_pdf * ms_prob
+	}
+}
+
+/// Microfacet model for rough dielectric interfaces, shared by the materials with such a lobe
+mod rough_dielectric {
+	use math::*;
+	use super::{BSDFSample, NULL_SAMPLE, cos_theta, fresnel, microfacet};
+
+	/// Relative IOR between the side of the incoming direction and the other one
+	fn relative_ior(ior: f32, cos_i: f32) -> f32 {
+		if cos_i > 0.0 { 1.0 / ior } else { ior }
+	}
+
+	/// Microfacet normal, on the upper side, for the given pair of directions
+	fn half_vector(eta: f32, dir_in: Vec3, dir_out: Vec3) -> Vec3 {
+		let h = if cos_theta(dir_in) * cos_theta(dir_out) < 0.0 {
+			(-dir_in + dir_out).normalized()
+		} else {
+			(-dir_in * eta + dir_out).normalized()
+		};
+		if cos_theta(h) < 0.0 { -h } else { h }
+	}
+
+	pub fn sample(ior: f32, roughness: (f32, f32), dir_in: Vec3, rnd: Vec3) -> BSDFSample {
+		let cos_i = -cos_theta(dir_in);
+		if cos_i == 0.0 {
+			return NULL_SAMPLE;
+		}
+		let eta = relative_ior(ior, cos_i);
+
+		// sample normals visible from the side of the incoming direction
+		let side = cos_i.signum();
+		let h = microfacet::sample_visible(roughness, -dir_in * side, (rnd.x, rnd.y));
+		let in_dot_h = -Vec3::dot(dir_in, h) * side;
+		if in_dot_h <= 0.0 {
+			return NULL_SAMPLE;
+		}
+		let (f, cos_t) = fresnel::dielectric_reflectance(eta, in_dot_h);
 
 		let is_reflection = rnd.z < f;
 		let direction = if is_reflection {
-			2.0 * in_dot_h * h + dir_in
+			2.0 * in_dot_h * h * side + dir_in
 		} else {
-			(eta_h * in_dot_h - in_dot_h.signum() * cos_t) * h - eta_h * -dir_in
+			((eta * in_dot_h - cos_t) * side) * h + eta * dir_in
 		}.normalized();
 
-		let reflected = cos_theta(dir_in) * cos_theta(direction) <= 0.0;
+		let reflected = cos_i * cos_theta(direction) > 0.0;
 		if reflected != is_reflection {
 			return NULL_SAMPLE;
 		}
 
-		let out_dot_h = Vec3::dot(direction, h);
-		let g = microfacet::shadowing(roughness, dir_in, direction, h);
-		let d = microfacet::distribution(roughness, cos_theta(h));
-		let weight = Vec3::thrice(in_dot_h.abs() * d * g / (ggx_pdf * cos_i));
-
-		let pdf = if is_reflection {
-			ggx_pdf / (4.0 * in_dot_h.abs()) * f
-		} else {
-			let x = eta * in_dot_h + out_dot_h;
-			ggx_pdf * out_dot_h.abs() / (x * x) * (1.0 - f)
-		};
+		// the shadowing from the incoming direction cancels out with the density of visible normals
+		let weight = Vec3::thrice(microfacet::shadowing_1d(roughness, direction, h));
+		let pdf = pdf(ior, roughness, dir_in, direction);
 
 		BSDFSample {
 			direction,
 			pdf,
-			weight: weight * self.albedo.eval(uv),
+			weight,
 			is_specular: false,
 		}
 	}
 
-	fn eval(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
+	pub fn eval(ior: f32, roughness: (f32, f32), dir_in: Vec3, dir_out: Vec3) -> f32 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
+		if cos_i == 0.0 || cos_o == 0.0 {
+			return 0.0;
+		}
 
-		let is_reflection = cos_i * cos_o >= 0.0;
-		let roughness = self.roughness.eval(uv).avg();
-		let eta = if cos_i < 0.0 { self.ior } else { 1.0 / self.ior };
-
-		let h = if is_reflection {
-			cos_i.signum() * (-dir_in + dir_out).normalized()
-		} else {
-			-(-dir_in*eta + dir_out).normalized()
-		};
+		let eta = relative_ior(ior, cos_i);
+		let h = half_vector(eta, dir_in, dir_out);
 		let in_dot_h = -Vec3::dot(dir_in, h);
 		let out_dot_h = Vec3::dot(dir_out, h);
-		let eta_h = if in_dot_h < 0.0 { self.ior } else { 1.0 / self.ior };
 
-		let (f, _) = fresnel::dielectric_reflectance(eta_h, in_dot_h.abs());
+		let (f, _) = fresnel::dielectric_reflectance(eta, in_dot_h.abs());
 		let g = microfacet::shadowing(roughness, dir_in, dir_out, h);
-		let d = microfacet::distribution(roughness, cos_theta(h));
+		let d = microfacet::distribution(roughness, h);
 
-		let r = if is_reflection {
+		if cos_i * cos_o > 0.0 {
 			(f * g * d) / (4.0 * cos_i.abs())
 		} else {
 			let x = eta * in_dot_h + out_dot_h;
 			(in_dot_h * out_dot_h).abs() * (1.0 - f) * g * d / (x * x * cos_i.abs())
-		};
-		r * self.albedo.eval(uv)
+		}
 	}
 
-	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> f32 {
+	pub fn pdf(ior: f32, roughness: (f32, f32), dir_in: Vec3, dir_out: Vec3) -> f32 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
+		if cos_i == 0.0 || cos_o == 0.0 {
+			return 0.0;
+		}
 
-		let is_reflection = cos_i * cos_o >= 0.0;
-		let roughness = self.roughness.eval(uv).avg();
-		let sample_roughness = (1.2 - 0.2 * cos_i.abs().sqrt()) * roughness;
-		let eta = if cos_i < 0.0 { self.ior } else { 1.0 / self.ior };
-
-		let h = if is_reflection {
-			cos_i.signum() * (-dir_in + dir_out).normalized()
-		} else {
-			-(-dir_in*eta + dir_out).normalized()
-		};
+		let eta = relative_ior(ior, cos_i);
+		let h = half_vector(eta, dir_in, dir_out);
 		let in_dot_h = -Vec3::dot(dir_in, h);
 		let out_dot_h = Vec3::dot(dir_out, h);
-		let eta_h = if in_dot_h < 0.0 { self.ior } else { 1.0 / self.ior };
 
-		let (f, _) = fresnel::dielectric_reflectance(eta_h, in_dot_h.abs());
-		let ggx_pdf = microfacet::pdf(sample_roughness, cos_theta(h));
+		let (f, _) = fresnel::dielectric_reflectance(eta, in_dot_h.abs());
+		let pdf_h = microfacet::pdf_visible(roughness, -dir_in * cos_i.signum(), h);
 
-		if is_reflection {
-			ggx_pdf / (4.0 * in_dot_h.abs()) * f
+		if cos_i * cos_o > 0.0 {
+			pdf_h * f / (4.0 * in_dot_h.abs())
+		} else if in_dot_h * out_dot_h >= 0.0 {
+			// cannot be obtained by refraction through the microfacet
+			0.0
 		} else {
 			let x = eta * in_dot_h + out_dot_h;
-			ggx_pdf * out_dot_h.abs() / (x * x) * (1.0 - f)
+			pdf_h * (1.0 - f) * out_dot_h.abs() / (x * x)
 		}
 	}
-
-	fn is_purely_specular(&self) -> bool {
-		false
-	}
 }
 
 pub struct Plastic {
@@ -257,7 +1129,7 @@ impl Plastic {
 }
 
 impl Material for Plastic {
-	fn eval(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
 
@@ -268,10 +1140,10 @@ impl Material for Plastic {
 		let eta = 1.0 / self.ior;
 		let (f, _) = fresnel::dielectric_reflectance(eta, cos_i);
 
-		self.albedo.eval(uv) * (INV_PI * cos_o * (1.0 - f))
+		self.albedo.eval(tc) * (INV_PI * cos_o * (1.0 - f))
 	}
 
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
 		let cos_i = -cos_theta(dir_in);
 		if cos_i <= 0.0 {
 			return NULL_SAMPLE;
@@ -300,13 +1172,13 @@ impl Material for Plastic {
 			BSDFSample {
 				direction,
 				pdf: cosine_hemisphere_pdf(direction) * (1.0 - spec_prob),
-				weight: self.albedo.eval(uv),
+				weight: self.albedo.eval(tc),
 				is_specular: false,
 			}
 		}
 	}
 
-	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, _uv: (f32, f32)) -> f32 {
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> f32 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
 
@@ -329,21 +1201,47 @@ impl Material for Plastic {
 pub struct RoughPlastic {
 	albedo: Texture,
 	ior: f32,
-	roughness: Texture,
+	roughness: Roughness,
+	/// Average Fresnel reflectance of the interface
+	avg_fresnel: f32,
+	/// Albedo of the glossy reflection, the remaining energy goes to the diffuse substrate
+	glossy_albedo: multiple_scattering::AlbedoTable,
 }
 
-impl RoughPlastic {
-	pub fn new(albedo: Texture, ior: f32, roughness: Texture) -> RoughPlastic {
+impl RoughPlastic {
+	pub fn new(albedo: Texture, ior: f32, roughness: Roughness) -> RoughPlastic {
+		let eta = 1.0 / ior;
+		let avg_fresnel = fresnel::dielectric_average(eta);
+		let glossy_albedo = multiple_scattering::AlbedoTable::compute(|alpha, dir_in, rnd| {
+			let h = microfacet::sample_visible(alpha, -dir_in, (rnd.x, rnd.y));
+			let in_dot_h = -Vec3::dot(dir_in, h);
+			let dir_out = 2.0 * in_dot_h * h + dir_in;
+			let single = if cos_theta(dir_out) > 0.0 {
+				let (f, _) = fresnel::dielectric_reflectance(eta, in_dot_h);
+				f * microfacet::shadowing_1d(alpha, dir_out, h)
+			} else {
+				0.0
+			};
+
+			// the albedo of the multiple scattering lobe is known
+			let table = multiple_scattering::ggx_albedo();
+			let e_avg = table.average(alpha.0);
+			let tint = multiple_scattering::fresnel_tint(Vec3::thrice(avg_fresnel), e_avg).x;
+			single + tint * (1.0 - table.albedo(-cos_theta(dir_in), alpha.0))
+		});
+
 		RoughPlastic {
 			albedo,
 			ior,
 			roughness,
+			avg_fresnel,
+			glossy_albedo,
 		}
 	}
 }
 
 impl Material for RoughPlastic {
-	fn eval(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
 
@@ -351,21 +1249,25 @@ impl Material for RoughPlastic {
 			return Vec3::zero();
 		}
 
-		let roughness = self.roughness.eval(uv).avg();
+		let roughness = self.roughness.eval(tc);
 		let h = (-dir_in + dir_out).normalized();
 		let eta = 1.0 / self.ior;
 
 		let (f, _) = fresnel::dielectric_reflectance(eta, -Vec3::dot(dir_in, h));
-		let d = microfacet::distribution(roughness, cos_theta(h));
+		let d = microfacet::distribution(roughness, h);
 		let g = microfacet::shadowing(roughness, dir_in, dir_out, h);
 
-		let spec_brdf = Vec3::thrice((f * d * g) / (4.0 * cos_i));
-		let diff_brdf = self.albedo.eval(uv) * (INV_PI * cos_o * (1.0 - f));
+		let alpha = multiple_scattering::table_alpha(roughness);
+		let table = multiple_scattering::ggx_albedo();
+		let tint = multiple_scattering::fresnel_tint(Vec3::thrice(self.avg_fresnel), table.average(alpha));
+
+		let spec_brdf = Vec3::thrice((f * d * g) / (4.0 * cos_i)) + tint * table.eval(alpha, cos_i, cos_o);
+		let diff_brdf = self.albedo.eval(tc) * self.glossy_albedo.eval(alpha, cos_i, cos_o);
 
 		spec_brdf + diff_brdf
 	}
 
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
 		let cos_i = -cos_theta(dir_in);
 		if cos_i <= 0.0 {
 			return NULL_SAMPLE;
@@ -377,8 +1279,8 @@ impl Material for RoughPlastic {
 		let spec_prob = 0.5;
 
 		let direction = if rnd.z < spec_prob {
-			let roughness = self.roughness.eval(uv).avg();
-			let (h, _) = microfacet::sample(roughness, (rnd.x, rnd.y));
+			let roughness = self.roughness.eval(tc);
+			let h = microfacet::sample_visible(roughness, -dir_in, (rnd.x, rnd.y));
 			let direction = (2.0 * -Vec3::dot(dir_in, h) * h + dir_in).normalized();
 
 			if cos_theta(direction) <= 0.0 {
@@ -390,17 +1292,17 @@ impl Material for RoughPlastic {
 			cosine_hemisphere((rnd.x, rnd.y))
 		};
 
-		let pdf = self.pdf(dir_in, direction, uv);
+		let pdf = self.pdf(dir_in, direction, tc);
 
 		BSDFSample {
 			direction,
 			pdf,
-			weight: self.eval(dir_in, direction, uv) / pdf,
+			weight: self.eval(dir_in, direction, tc) / pdf,
 			is_specular: false,
 		}
 	}
 
-	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> f32 {
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
 
@@ -410,10 +1312,10 @@ impl Material for RoughPlastic {
 
 		let spec_prob = 0.5;
 
-		let roughness = self.roughness.eval(uv).avg();
+		let roughness = self.roughness.eval(tc);
 		let h = (-dir_in + dir_out).normalized();
 
-		let spec_pdf = microfacet::pdf(roughness, cos_theta(h)) / (4.0 * Vec3::dot(dir_out, h));
+		let spec_pdf = microfacet::pdf_visible(roughness, -dir_in, h) / (4.0 * Vec3::dot(dir_out, h));
 		let diff_pdf = cosine_hemisphere_pdf(dir_out);
 
 		spec_pdf * spec_prob + diff_pdf * (1.0 - spec_prob)
@@ -427,18 +1329,19 @@ impl Material for RoughPlastic {
 pub struct Conductor {
 	pub albedo: Texture,
 	pub ior: ComplexIOR,
+	pub thin_film: Option<ThinFilm>,
 }
 
 
 impl Conductor {
 	pub fn from_symbol(symbol: &str, albedo: Texture) -> Option<Arc<Material>> {
-		let &(_, ior) = CONDUCTORS_IOR.iter().find(|t| t.0 == symbol)?;
-		Some(Arc::new(Conductor { albedo, ior }))
+		let ior = ComplexIOR::from_symbol(symbol)?;
+		Some(Arc::new(Conductor { albedo, ior, thin_film: None }))
 	}
 }
 
 impl Material for Conductor {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), _rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, _rnd: Vec3) -> BSDFSample {
 		let cos_i = -cos_theta(dir_in);
 
 		if cos_i <= 0.0 {
@@ -448,7 +1351,7 @@ impl Material for Conductor {
 		BSDFSample {
 			direction: reflect(dir_in),
 			pdf: 1.0,
-			weight: self.albedo.eval(uv) * fresnel::conductor_reflectance_rgb(self.ior, cos_i),
+			weight: self.albedo.eval(tc) * conductor_reflectance(self.ior, &self.thin_film, tc, cos_i),
 			is_specular: true,
 		}
 	}
@@ -457,65 +1360,91 @@ impl Material for Conductor {
 pub struct RoughConductor {
 	pub albedo: Texture,
 	pub ior: ComplexIOR,
-	pub roughness: Texture,
+	pub roughness: Roughness,
+	/// The energy compensation still uses the average reflectance of the bare conductor
+	pub thin_film: Option<ThinFilm>,
 }
 
 impl RoughConductor {
-	pub fn from_symbol(symbol: &str, albedo: Texture, roughness: Texture) -> Option<Arc<Material>> {
-		let &(_, ior) = CONDUCTORS_IOR.iter().find(|t| t.0 == symbol)?;
-		Some(Arc::new(RoughConductor { albedo, ior, roughness }))
+	pub fn from_symbol(symbol: &str, albedo: Texture, roughness: Roughness) -> Option<Arc<Material>> {
+		let ior = ComplexIOR::from_symbol(symbol)?;
+		Some(Arc::new(RoughConductor { albedo, ior, roughness, thin_film: None }))
+	}
+
+	/// Average Fresnel reflectance, for the energy compensation
+	fn avg_fresnel(&self) -> Vec3 {
+		static AVERAGES: multiple_scattering::Cache<ComplexIOR, Vec3> = multiple_scattering::Cache::new();
+		*AVERAGES.get(self.ior, || fresnel::conductor_average_rgb(self.ior))
+	}
+
+	/// Probability of sampling the multiple scattering lobe
+	fn multiple_scattering_prob(roughness: (f32, f32), cos_i: f32) -> f32 {
+		let alpha = multiple_scattering::table_alpha(roughness);
+		1.0 - multiple_scattering::ggx_albedo().albedo(cos_i, alpha)
 	}
 }
 
 impl Material for RoughConductor {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
-		let roughness = self.roughness.eval(uv).avg();
-		let (h, ggx_pdf) = microfacet::sample(roughness, (rnd.x, rnd.y));
-		let in_dot_h = -Vec3::dot(dir_in, h);
-		let direction = dir_in + (2.0 * in_dot_h) * h;
-
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
 		let cos_i = -cos_theta(dir_in);
-		let cos_o = cos_theta(direction);
-		if cos_i <= 0.0 || cos_o <= 0.0 || in_dot_h <= 0.0 {
+		if cos_i <= 0.0 {
 			return NULL_SAMPLE;
 		}
 
-		let g = microfacet::shadowing(roughness, dir_in, direction, h);
-		let d = microfacet::distribution(roughness, cos_theta(h));
-		let f = fresnel::conductor_reflectance_rgb(self.ior, in_dot_h);
+		let roughness = self.roughness.eval(tc);
+		let direction = if rnd.z < RoughConductor::multiple_scattering_prob(roughness, cos_i) {
+			cosine_hemisphere((rnd.x, rnd.y))
+		} else {
+			let h = microfacet::sample_visible(roughness, -dir_in, (rnd.x, rnd.y));
+			dir_in + (2.0 * -Vec3::dot(dir_in, h)) * h
+		};
 
-		let pdf = ggx_pdf / (4.0 * in_dot_h);
-		let weight = in_dot_h * d * g / (ggx_pdf * cos_i);
-		let weight = self.albedo.eval(uv) * (f * weight);
+		if cos_theta(direction) <= 0.0 {
+			return NULL_SAMPLE;
+		}
 
-		BSDFSample { direction, pdf, weight, is_specular: false }
+		let pdf = self.pdf(dir_in, direction, tc);
+		BSDFSample {
+			direction,
+			pdf,
+			weight: self.eval(dir_in, direction, tc) / pdf,
+			is_specular: false,
+		}
 	}
 
-	fn eval(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
-		if cos_i <= 0.0 && cos_o <= 0.0 {
+		if cos_i <= 0.0 || cos_o <= 0.0 {
 			return Vec3::zero();
 		}
 
-		let roughness = self.roughness.eval(uv).avg();
+		let roughness = self.roughness.eval(tc);
 		let h = (-dir_in + dir_out).normalized();
-		let f = fresnel::conductor_reflectance_rgb(self.ior, -Vec3::dot(dir_in, h));
+		let f = conductor_reflectance(self.ior, &self.thin_film, tc, -Vec3::dot(dir_in, h));
 		let g = microfacet::shadowing(roughness, dir_in, dir_out, h);
-		let d = microfacet::distribution(roughness, cos_theta(h));
-		let albedo = self.albedo.eval(uv);
-		albedo * f * (g * d / (4.0 * cos_i))
+		let d = microfacet::distribution(roughness, h);
+		let single = f * (g * d / (4.0 * cos_i));
+
+		let alpha = multiple_scattering::table_alpha(roughness);
+		let table = multiple_scattering::ggx_albedo();
+		let tint = multiple_scattering::fresnel_tint(self.avg_fresnel(), table.average(alpha));
+		let multiple = tint * table.eval(alpha, cos_i, cos_o);
+
+		self.albedo.eval(tc) * (single + multiple)
 	}
 
-	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> f32 {
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
 		let cos_i = -cos_theta(dir_in);
 		let cos_o = cos_theta(dir_out);
-		if cos_i <= 0.0 && cos_o <= 0.0 {
+		if cos_i <= 0.0 || cos_o <= 0.0 {
 			return 0.0;
 		}
-		let roughness = self.roughness.eval(uv).avg();
+		let roughness = self.roughness.eval(tc);
 		let h = (-dir_in + dir_out).normalized();
-		microfacet::pdf(roughness, cos_theta(h)) / (4.0 * -Vec3::dot(dir_in, h))
+		let single_pdf = microfacet::pdf_visible(roughness, -dir_in, h) / (4.0 * -Vec3::dot(dir_in, h));
+		let ms_prob = RoughConductor::multiple_scattering_prob(roughness, cos_i);
+		single_pdf * (1.0 - ms_prob) + cosine_hemisphere_pdf(dir_out) * ms_prob
 	}
 
 	fn is_purely_specular(&self) -> bool {
@@ -530,7 +1459,7 @@ pub struct SmoothCoat {
 }
 
 impl Material for SmoothCoat {
-	fn sample(&self, dir_in: Vec3, uv: (f32, f32), rnd: Vec3) -> BSDFSample {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
 		let eta = 1.0 / self.ior;
 		let cos_i = -cos_theta(dir_in);
 		if cos_i <= 0.0 {
@@ -552,75 +1481,618 @@ impl Material for SmoothCoat {
 			};
 		}
 
-		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
-		let sub_sample = self.substrate.sample(dir_in_sub, uv, rnd);
-		if sub_sample.weight == Vec3::zero() {
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let sub_sample = self.substrate.sample(dir_in_sub, tc, rnd);
+		if sub_sample.weight == Vec3::zero() {
+			return NULL_SAMPLE;
+		}
+
+		let cos_sub = cos_theta(sub_sample.direction);
+		let (fo, cos_to) = fresnel::dielectric_reflectance(self.ior, cos_sub);
+		if fo == 1.0 {
+			return NULL_SAMPLE;
+		}
+
+		let dir_out_sub = sub_sample.direction;
+		let direction = Vec3::new(dir_out_sub.x * self.ior, cos_to, dir_out_sub.z * self.ior);
+
+		let mut weight = sub_sample.weight * ((1.0 - fi) * (1.0 - fo));
+		if self.scaled_sigma_a.max_elem() > 0.0 {
+			weight *= (self.scaled_sigma_a * (-1.0 / cos_sub - 1.0 / cos_ti)).map(f32::exp);
+		}
+		weight = weight / (1.0 - specular_prob);
+		let pdf = sub_sample.pdf * (1.0 - specular_prob) * eta*eta*cos_to/cos_sub;
+
+		BSDFSample { direction, pdf, weight, is_specular: sub_sample.is_specular }
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return Vec3::zero();
+		}
+
+		let eta = 1.0 / self.ior;
+		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
+		let (fo, cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+
+		let mut sub_eval = self.substrate.eval(dir_in_sub, dir_out_sub, tc);
+
+		if self.scaled_sigma_a.max_elem() > 0.0 {
+			sub_eval *= (self.scaled_sigma_a * (-1.0 / cos_to - 1.0 / cos_ti)).map(f32::exp);
+		}
+
+		let l = eta * eta * cos_o / cos_to;
+		l * (1.0 - fi) * (1.0 - fo) * sub_eval
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return 0.0;
+		}
+
+		let eta = 1.0 / self.ior;
+		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
+		let (_,  cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+
+		let avg_transmittance = (-2.0 * self.scaled_sigma_a.avg()).exp();
+		let sub_weight = avg_transmittance * (1.0 - fi);
+		let specular_weight = fi;
+		let specular_prob = specular_weight / (specular_weight + sub_weight);
+		let l = eta * eta * (cos_o / cos_to).abs();
+		self.substrate.pdf(dir_in_sub, dir_out_sub, tc) * (1.0 - specular_prob) * l
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		false
+	}
+}
+
+/// Rough dielectric coating, with a GGX interface, over an arbitrary substrate
+///
+/// Light reaching the substrate is refracted by the average, smooth interface.
+pub struct RoughCoat {
+	pub ior: f32,
+	pub scaled_sigma_a: Vec3,
+	pub roughness: Roughness,
+	pub substrate: Arc<Material>,
+}
+
+impl RoughCoat {
+	/// Probability of sampling the glossy reflection rather than the substrate
+	fn glossy_prob(&self, fi: f32) -> f32 {
+		let avg_transmittance = (-2.0 * self.scaled_sigma_a.avg()).exp();
+		let sub_weight = avg_transmittance * (1.0 - fi);
+		fi / (fi + sub_weight)
+	}
+
+	fn eval_glossy(&self, roughness: (f32, f32), dir_in: Vec3, dir_out: Vec3) -> f32 {
+		let cos_i = -cos_theta(dir_in);
+		let h = (-dir_in + dir_out).normalized();
+		let (f, _) = fresnel::dielectric_reflectance(1.0 / self.ior, -Vec3::dot(dir_in, h));
+		let d = microfacet::distribution(roughness, h);
+		let g = microfacet::shadowing(roughness, dir_in, dir_out, h);
+		f * d * g / (4.0 * cos_i)
+	}
+
+	fn pdf_glossy(&self, roughness: (f32, f32), dir_in: Vec3, dir_out: Vec3) -> f32 {
+		let h = (-dir_in + dir_out).normalized();
+		microfacet::pdf_visible(roughness, -dir_in, h) / (4.0 * Vec3::dot(dir_out, h))
+	}
+}
+
+impl Material for RoughCoat {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		let eta = 1.0 / self.ior;
+		let cos_i = -cos_theta(dir_in);
+		if cos_i <= 0.0 {
+			return NULL_SAMPLE;
+		}
+		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
+		let glossy_prob = self.glossy_prob(fi);
+
+		if rnd.z < glossy_prob {
+			let roughness = self.roughness.eval(tc);
+			let h = microfacet::sample_visible(roughness, -dir_in, (rnd.x, rnd.y));
+			let direction = (2.0 * -Vec3::dot(dir_in, h) * h + dir_in).normalized();
+			if cos_theta(direction) <= 0.0 {
+				return NULL_SAMPLE;
+			}
+
+			let pdf = self.pdf(dir_in, direction, tc);
+			return BSDFSample {
+				direction,
+				pdf,
+				weight: self.eval(dir_in, direction, tc) / pdf,
+				is_specular: false,
+			};
+		}
+
+		let rnd_z = (rnd.z - glossy_prob) / (1.0 - glossy_prob);
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let sub_sample = self.substrate.sample(dir_in_sub, tc, Vec3::new(rnd.x, rnd.y, rnd_z));
+		if sub_sample.weight == Vec3::zero() {
+			return NULL_SAMPLE;
+		}
+
+		let cos_sub = cos_theta(sub_sample.direction);
+		let (fo, cos_to) = fresnel::dielectric_reflectance(self.ior, cos_sub);
+		if fo == 1.0 {
+			return NULL_SAMPLE;
+		}
+
+		let dir_out_sub = sub_sample.direction;
+		let direction = Vec3::new(dir_out_sub.x * self.ior, cos_to, dir_out_sub.z * self.ior);
+
+		if !sub_sample.is_specular {
+			// the glossy reflection may also produce this direction
+			let pdf = self.pdf(dir_in, direction, tc);
+			return BSDFSample {
+				direction,
+				pdf,
+				weight: self.eval(dir_in, direction, tc) / pdf,
+				is_specular: false,
+			};
+		}
+
+		let mut weight = sub_sample.weight * ((1.0 - fi) * (1.0 - fo));
+		if self.scaled_sigma_a.max_elem() > 0.0 {
+			weight *= (self.scaled_sigma_a * (-1.0 / cos_sub - 1.0 / cos_ti)).map(f32::exp);
+		}
+		weight = weight / (1.0 - glossy_prob);
+		let pdf = sub_sample.pdf * (1.0 - glossy_prob) * eta*eta*cos_to/cos_sub;
+
+		BSDFSample { direction, pdf, weight, is_specular: true }
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return Vec3::zero();
+		}
+
+		let roughness = self.roughness.eval(tc);
+		let glossy = self.eval_glossy(roughness, dir_in, dir_out);
+
+		let eta = 1.0 / self.ior;
+		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
+		let (fo, cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+
+		let mut sub_eval = self.substrate.eval(dir_in_sub, dir_out_sub, tc);
+
+		if self.scaled_sigma_a.max_elem() > 0.0 {
+			sub_eval *= (self.scaled_sigma_a * (-1.0 / cos_to - 1.0 / cos_ti)).map(f32::exp);
+		}
+
+		let l = eta * eta * cos_o / cos_to;
+		Vec3::thrice(glossy) + l * (1.0 - fi) * (1.0 - fo) * sub_eval
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return 0.0;
+		}
+
+		let roughness = self.roughness.eval(tc);
+		let glossy_pdf = self.pdf_glossy(roughness, dir_in, dir_out);
+
+		let eta = 1.0 / self.ior;
+		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
+		let (_,  cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+
+		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
+		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+
+		let glossy_prob = self.glossy_prob(fi);
+		let l = eta * eta * (cos_o / cos_to).abs();
+		let sub_pdf = self.substrate.pdf(dir_in_sub, dir_out_sub, tc) * l;
+		glossy_pdf * glossy_prob + sub_pdf * (1.0 - glossy_prob)
+	}
+
+	fn is_purely_specular(&self) -> bool {
+		false
+	}
+}
+
+/// Principled material in the spirit of Disney's BRDF, as authored in most DCC tools
+///
+/// All parameters but the base color are scalar, stored in textures whose channels are averaged.
+pub struct Principled {
+	pub base_color: Texture,
+	pub metallic: Texture,
+	pub roughness: Texture,
+	/// Scales the reflectance at normal incidence of dielectrics, 0.5 maps to 4%
+	pub specular: Texture,
+	pub sheen: Texture,
+	pub clearcoat: Texture,
+	pub transmission: Texture,
+	pub ior: f32,
+}
+
+/// Parameters of a principled material at a given point
+struct PrincipledParams {
+	base_color: Vec3,
+	alpha: (f32, f32),
+	specular_color: Vec3,
+	sheen: f32,
+	clearcoat: f32,
+	diffuse_weight: f32,
+	transmission_weight: f32,
+	roughness: f32,
+}
+
+/// Roughness of the clearcoat layer
+const CLEARCOAT_ALPHA: (f32, f32) = (0.05, 0.05);
+
+/// Upper bound of the albedo of the diffuse lobe of principled materials
+const PRINCIPLED_MAX_DIFFUSE_ALBEDO: f32 = 4.0;
+
+impl Principled {
+	fn params(&self, tc: TexCoords) -> PrincipledParams {
+		let base_color = self.base_color.eval(tc);
+		let metallic = self.metallic.eval(tc).avg();
+		let roughness = self.roughness.eval(tc).avg();
+		let transmission = self.transmission.eval(tc).avg();
+
+		let dielectric_f0 = Vec3::thrice(0.08 * self.specular.eval(tc).avg());
+		let alpha = (roughness * roughness).max(0.001);
+		PrincipledParams {
+			base_color,
+			alpha: (alpha, alpha),
+			specular_color: dielectric_f0 * (1.0 - metallic) + base_color * metallic,
+			sheen: self.sheen.eval(tc).avg(),
+			clearcoat: self.clearcoat.eval(tc).avg(),
+			diffuse_weight: (1.0 - metallic) * (1.0 - transmission),
+			transmission_weight: (1.0 - metallic) * transmission,
+			roughness,
+		}
+	}
+
+	/// Probabilities of sampling the diffuse, specular, clearcoat and transmission lobes
+	fn lobe_probs(p: &PrincipledParams, front: bool) -> [f32; 4] {
+		if !front {
+			return [0.0, 0.0, 0.0, if p.transmission_weight > 0.0 { 1.0 } else { 0.0 }];
+		}
+		let weights = [
+			p.diffuse_weight,
+			1.0 - p.transmission_weight,
+			0.25 * p.clearcoat,
+			p.transmission_weight,
+		];
+		let total: f32 = weights.iter().sum();
+		[weights[0] / total, weights[1] / total, weights[2] / total, weights[3] / total]
+	}
+
+	/// Directional albedo of a GGX lobe with Schlick's approximation of the Fresnel factor
+	fn lobe_albedo(f0: Vec3, alpha: (f32, f32), cos_i: f32) -> Vec3 {
+		let alpha = multiple_scattering::table_alpha(alpha);
+		let unit = multiple_scattering::ggx_albedo().albedo(cos_i, alpha);
+		let schlick = multiple_scattering::ggx_schlick_albedo().albedo(cos_i, alpha);
+		f0 * unit + (Vec3::thrice(1.0) - f0) * schlick
+	}
+
+	/// Diffuse reflectance relative to a Lambertian one, with retro-reflection at grazing angles
+	fn diffuse_factor(roughness: f32, cos_i: f32, cos_o: f32, cos_d: f32) -> f32 {
+		let fd90 = 0.5 + 2.0 * roughness * cos_d * cos_d;
+		let weight = multiple_scattering::schlick_weight;
+		(1.0 + (fd90 - 1.0) * weight(cos_i)) * (1.0 + (fd90 - 1.0) * weight(cos_o))
+	}
+
+	/// Directional albedos of the diffuse lobe, tabulated by roughness, and of a unit sheen lobe
+	///
+	/// Both lobes reflect more light than they receive at grazing angles.
+	fn diffuse_albedos() -> &'static (multiple_scattering::AlbedoTable, multiple_scattering::AlbedoTable) {
+		static TABLES: OnceLock<(multiple_scattering::AlbedoTable, multiple_scattering::AlbedoTable)> = OnceLock::new();
+		TABLES.get_or_init(|| {
+			let cos_d = |dir_in: Vec3, dir_out: Vec3| Vec3::dot(dir_out, (-dir_in + dir_out).normalized());
+			// with cosine weighted outgoing directions
+			let diffuse = multiple_scattering::AlbedoTable::compute_with_max(|(roughness, _), dir_in, rnd| {
+				let dir_out = cosine_hemisphere((rnd.x, rnd.y));
+				Principled::diffuse_factor(roughness, -cos_theta(dir_in), cos_theta(dir_out), cos_d(dir_in, dir_out))
+			}, PRINCIPLED_MAX_DIFFUSE_ALBEDO);
+			let sheen = multiple_scattering::AlbedoTable::compute(|_, dir_in, rnd| {
+				let dir_out = cosine_hemisphere((rnd.x, rnd.y));
+				PI * multiple_scattering::schlick_weight(cos_d(dir_in, dir_out))
+			});
+			(diffuse, sheen)
+		})
+	}
+
+	fn eval_params(&self, p: &PrincipledParams, dir_in: Vec3, dir_out: Vec3) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+
+		// Each layer only receives the light not reflected by the ones above it: the clearcoat
+		// covers the base, whose specular reflection covers the diffuse layer.
+		let coat_transmittance = if cos_i > 0.0 && p.clearcoat > 0.0 {
+			1.0 - 0.25 * p.clearcoat * Principled::lobe_albedo(Vec3::thrice(0.04), CLEARCOAT_ALPHA, cos_i).x
+		} else {
+			1.0
+		};
+
+		let mut value = Vec3::zero();
+		if p.transmission_weight > 0.0 {
+			let t = rough_dielectric::eval(self.ior, p.alpha, dir_in, dir_out);
+			value += p.base_color * (t * p.transmission_weight * coat_transmittance);
+		}
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return value;
+		}
+
+		let h = (-dir_in + dir_out).normalized();
+		let cos_d = Vec3::dot(dir_out, h);
+		let schlick_weight = multiple_scattering::schlick_weight;
+
+		// diffuse with retro-reflection at grazing angles, plus sheen, normalized where they
+		// would reflect more light than they receive
+		let fd = Principled::diffuse_factor(p.roughness, cos_i, cos_o, cos_d);
+		let diffuse = p.base_color * (INV_PI * fd) + p.base_color * (p.sheen * schlick_weight(cos_d));
+		let (diffuse_albedo, sheen_albedo) = Principled::diffuse_albedos();
+		let albedo = diffuse_albedo.albedo(cos_i, p.roughness) + p.sheen * sheen_albedo.albedo(cos_i, 0.0);
+		let specular_transmittance = Vec3::thrice(1.0) - Principled::lobe_albedo(p.specular_color, p.alpha, cos_i);
+		value += diffuse * specular_transmittance * (p.diffuse_weight * coat_transmittance * cos_o / albedo.max(1.0));
+
+		// specular reflection, with Schlick's approximation of the Fresnel factor
+		let f = p.specular_color + (Vec3::thrice(1.0) - p.specular_color) * schlick_weight(cos_d);
+		let d = microfacet::distribution(p.alpha, h);
+		let g = microfacet::shadowing(p.alpha, dir_in, dir_out, h);
+		value += f * ((1.0 - p.transmission_weight) * coat_transmittance * d * g / (4.0 * cos_i));
+
+		// clearcoat
+		if p.clearcoat > 0.0 {
+			let f = 0.04 + 0.96 * schlick_weight(cos_d);
+			let d = microfacet::distribution(CLEARCOAT_ALPHA, h);
+			let g = microfacet::shadowing(CLEARCOAT_ALPHA, dir_in, dir_out, h);
+			value += Vec3::thrice(0.25 * p.clearcoat * f * d * g / (4.0 * cos_i));
+		}
+
+		value
+	}
+
+	fn pdf_params(&self, p: &PrincipledParams, dir_in: Vec3, dir_out: Vec3) -> f32 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		let probs = Principled::lobe_probs(p, cos_i > 0.0);
+
+		let mut pdf = 0.0;
+		if probs[3] > 0.0 {
+			pdf += probs[3] * rough_dielectric::pdf(self.ior, p.alpha, dir_in, dir_out);
+		}
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return pdf;
+		}
+
+		let h = (-dir_in + dir_out).normalized();
+		let out_dot_h = Vec3::dot(dir_out, h);
+		pdf += probs[0] * cosine_hemisphere_pdf(dir_out);
+		pdf += probs[1] * microfacet::pdf_visible(p.alpha, -dir_in, h) / (4.0 * out_dot_h);
+		pdf += probs[2] * microfacet::pdf_visible(CLEARCOAT_ALPHA, -dir_in, h) / (4.0 * out_dot_h);
+		pdf
+	}
+}
+
+impl Material for Principled {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		let p = self.params(tc);
+		let probs = Principled::lobe_probs(&p, -cos_theta(dir_in) > 0.0);
+
+		// Pick a lobe, and remap the random number used to do so
+		let mut lobe = 0;
+		let mut z = rnd.z;
+		while lobe < 3 && (z >= probs[lobe] || probs[lobe] == 0.0) {
+			z -= probs[lobe];
+			lobe += 1;
+		}
+		if probs[lobe] == 0.0 {
+			return NULL_SAMPLE;
+		}
+		let z = (z / probs[lobe]).min(1.0 - EPSILON).max(0.0);
+
+		let direction = match lobe {
+			0 => cosine_hemisphere((rnd.x, rnd.y)),
+			1 | 2 => {
+				let alpha = if lobe == 1 { p.alpha } else { CLEARCOAT_ALPHA };
+				let h = microfacet::sample_visible(alpha, -dir_in, (rnd.x, rnd.y));
+				let direction = (2.0 * -Vec3::dot(dir_in, h) * h + dir_in).normalized();
+				if cos_theta(direction) <= 0.0 {
+					return NULL_SAMPLE;
+				}
+				direction
+			}
+			_ => {
+				let sample = rough_dielectric::sample(self.ior, p.alpha, dir_in, Vec3::new(rnd.x, rnd.y, z));
+				if sample.weight == Vec3::zero() {
+					return NULL_SAMPLE;
+				}
+				sample.direction
+			}
+		};
+
+		let pdf = self.pdf_params(&p, dir_in, direction);
+		if pdf <= 0.0 {
 			return NULL_SAMPLE;
 		}
 
-		let cos_sub = cos_theta(sub_sample.direction);
-		let (fo, cos_to) = fresnel::dielectric_reflectance(self.ior, cos_sub);
-		if fo == 1.0 {
-			return NULL_SAMPLE;
+		BSDFSample {
+			direction,
+			pdf,
+			weight: self.eval_params(&p, dir_in, direction) / pdf,
+			is_specular: false,
 		}
+	}
 
-		let dir_out_sub = sub_sample.direction;
-		let direction = Vec3::new(dir_out_sub.x * self.ior, cos_to, dir_out_sub.z * self.ior);
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> Vec3 {
+		self.eval_params(&self.params(tc), dir_in, dir_out)
+	}
 
-		let mut weight = sub_sample.weight * ((1.0 - fi) * (1.0 - fo));
-		if self.scaled_sigma_a.max_elem() > 0.0 {
-			weight *= (self.scaled_sigma_a * (-1.0 / cos_sub - 1.0 / cos_ti)).map(f32::exp);
-		}
-		weight = weight / (1.0 - specular_prob);
-		let pdf = sub_sample.pdf * (1.0 - specular_prob) * eta*eta*cos_to/cos_sub;
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, tc: TexCoords) -> f32 {
+		self.pdf_params(&self.params(tc), dir_in, dir_out)
+	}
 
-		BSDFSample { direction, pdf, weight, is_specular: sub_sample.is_specular }
+	fn is_purely_specular(&self) -> bool {
+		false
 	}
+}
 
-	fn eval(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> Vec3 {
-		let cos_i = -cos_theta(dir_in);
-		let cos_o = cos_theta(dir_out);
-		if cos_i <= 0.0 && cos_o <= 0.0 {
-			return Vec3::zero();
-		}
+/// Isotropic BRDF measured by Matusik et al., tabulated in the half/difference
+/// angles parameterization of the MERL database
+///
+/// Directions are sampled by mixing a cosine lobe with a distribution of half
+/// vectors tabulated from the average measurements.
+pub struct Measured {
+	table: Vec<Vec3>,
+	half_dist: Distribution1D,
+}
 
-		let eta = 1.0 / self.ior;
-		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
-		let (fo, cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+/// Resolution of the MERL tables along theta_half, theta_diff and phi_diff
+pub(crate) const MERL_RES: (usize, usize, usize) = (90, 90, 180);
 
-		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
-		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+/// Probability of sampling the tabulated half vector distribution rather than the cosine lobe
+const MEASURED_HALF_PROB: f32 = 0.8;
 
-		let mut sub_eval = self.substrate.eval(dir_in_sub, dir_out_sub, uv);
+impl Measured {
+	/// Load a binary file of the MERL BRDF database
+	pub fn load_merl<P: AsRef<Path>>(path: P) -> Measured {
+		let mut bytes = Vec::new();
+		File::open(&path).expect("failed to open MERL BRDF")
+			.read_to_end(&mut bytes).expect("failed to read MERL BRDF");
 
-		if self.scaled_sigma_a.max_elem() > 0.0 {
-			sub_eval *= (self.scaled_sigma_a * (-1.0 / cos_to - 1.0 / cos_ti)).map(f32::exp);
+		let int = |i: usize| {
+			let mut b = [0u8; 4];
+			b.copy_from_slice(&bytes[4 * i..4 * i + 4]);
+			i32::from_le_bytes(b) as usize
+		};
+		let (res_h, res_d, res_phi) = MERL_RES;
+		assert!(bytes.len() >= 12 && (int(0), int(1), int(2)) == (res_h, res_d, res_phi), "invalid MERL BRDF dimensions");
+
+		let n = res_h * res_d * res_phi;
+		assert_eq!(bytes.len(), 12 + 3 * n * 8, "invalid MERL BRDF size");
+		let float = |i: usize| {
+			let mut b = [0u8; 8];
+			b.copy_from_slice(&bytes[12 + 8 * i..12 + 8 * i + 8]);
+			// negative values mark missing measurements
+			f64::from_le_bytes(b).max(0.0) as f32
+		};
+		let scale = Vec3::new(1.0, 1.15, 1.66) / 1500.0;
+		Measured::from_table((0..n).map(|i| Vec3::new(float(i), float(n + i), float(2 * n + i)) * scale).collect())
+	}
+
+	/// Measured BRDF from a table of values, indexed by theta_half, theta_diff and phi_diff
+	pub(crate) fn from_table(table: Vec<Vec3>) -> Measured {
+		let (res_h, res_d, res_phi) = MERL_RES;
+
+		// Sample the half vector proportionally to the average BRDF times cos(theta_half),
+		// with respect to the parameter u = sqrt(2 theta_half / pi) of the table
+		let weights = (0..res_h).map(|i| {
+			let row = &table[i * res_d * res_phi..(i + 1) * res_d * res_phi];
+			let avg = row.iter().map(|v| v.avg()).sum::<f32>() / row.len() as f32;
+			let u = (i as f32 + 0.5) / res_h as f32;
+			let theta_h = u * u * FRAC_PI_2;
+			avg * theta_h.cos() * theta_h.sin() * u
+		}).collect();
+
+		Measured { table, half_dist: Distribution1D::new(weights) }
+	}
+
+	/// Tabulated value for the directions `wi` and `wo`, both pointing away from the surface
+	fn lookup(&self, wi: Vec3, wo: Vec3) -> Vec3 {
+		let h = (wi + wo).normalized();
+		let theta_h = h.y.min(1.0).acos();
+		let phi_h = h.x.atan2(h.z);
+
+		// rotate `wi` around the normal to bring the half vector in the YZ plane, then around
+		// the X axis to align it with the normal
+		let (s, c) = phi_h.sin_cos();
+		let t = Vec3::new(wi.x * c - wi.z * s, wi.y, wi.x * s + wi.z * c);
+		let (s, c) = (-theta_h).sin_cos();
+		let d = Vec3::new(t.x, t.y * c - t.z * s, t.y * s + t.z * c);
+
+		let theta_d = d.y.max(-1.0).min(1.0).acos();
+		let mut phi_d = d.x.atan2(d.z);
+		if phi_d < 0.0 {
+			phi_d += PI;
 		}
 
-		let l = eta * eta * cos_o / cos_to;
-		l * (1.0 - fi) * (1.0 - fo) * sub_eval
+		let (res_h, res_d, res_phi) = MERL_RES;
+		let index = |v: f32, res: usize| (v.max(0.0) as usize).min(res - 1);
+		let ih = index((theta_h / FRAC_PI_2).sqrt() * res_h as f32, res_h);
+		let id = index(theta_d / FRAC_PI_2 * res_d as f32, res_d);
+		let iphi = index(phi_d / PI * res_phi as f32, res_phi);
+
+		self.table[(ih * res_d + id) * res_phi + iphi]
 	}
 
-	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, uv: (f32, f32)) -> f32 {
-		let cos_i = -cos_theta(dir_in);
-		let cos_o = cos_theta(dir_out);
-		if cos_i <= 0.0 && cos_o <= 0.0 {
+	/// PDF of sampling the direction `wo` from the half vector distribution
+	fn pdf_half(&self, wi: Vec3, wo: Vec3) -> f32 {
+		let h = (wi + wo).normalized();
+		let theta_h = h.y.min(1.0).acos();
+		let u = (theta_h / FRAC_PI_2).sqrt();
+		let sin_h = theta_h.sin();
+		if u <= 0.0 || sin_h <= 0.0 {
 			return 0.0;
 		}
+		let pdf_h = self.half_dist.pdf_continuous(u) / (u * PI * sin_h * 2.0 * PI);
+		pdf_h / (4.0 * Vec3::dot(wo, h).abs())
+	}
+}
 
-		let eta = 1.0 / self.ior;
-		let (fi, cos_ti) = fresnel::dielectric_reflectance(eta, cos_i);
-		let (_,  cos_to) = fresnel::dielectric_reflectance(eta, cos_o);
+impl Material for Measured {
+	fn sample(&self, dir_in: Vec3, tc: TexCoords, rnd: Vec3) -> BSDFSample {
+		if -cos_theta(dir_in) <= 0.0 {
+			return NULL_SAMPLE;
+		}
 
-		let dir_in_sub = Vec3::new(dir_in.x * eta, -cos_ti, dir_in.z * eta);
-		let dir_out_sub = Vec3::new(dir_out.x * eta, cos_to, dir_out.z * eta);
+		let direction = if rnd.z < MEASURED_HALF_PROB {
+			let (u, _) = self.half_dist.sample_continuous(rnd.x);
+			let theta_h = u * u * FRAC_PI_2;
+			let phi_h = 2.0 * PI * rnd.y;
+			let h = Vec3::new(theta_h.sin() * phi_h.sin(), theta_h.cos(), theta_h.sin() * phi_h.cos());
+			(2.0 * -Vec3::dot(dir_in, h) * h + dir_in).normalized()
+		} else {
+			cosine_hemisphere((rnd.x, rnd.y))
+		};
 
-		let avg_transmittance = (-2.0 * self.scaled_sigma_a.avg()).exp();
-		let sub_weight = avg_transmittance * (1.0 - fi);
-		let specular_weight = fi;
-		let specular_prob = specular_weight / (specular_weight + sub_weight);
-		let l = eta * eta * (cos_o / cos_to).abs();
-		self.substrate.pdf(dir_in_sub, dir_out_sub, uv) * (1.0 - specular_prob) * l
+		let pdf = self.pdf(dir_in, direction, tc);
+		if pdf <= 0.0 {
+			return NULL_SAMPLE;
+		}
+		BSDFSample {
+			direction,
+			pdf,
+			weight: self.eval(dir_in, direction, tc) / pdf,
+			is_specular: false,
+		}
+	}
+
+	fn eval(&self, dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> Vec3 {
+		let cos_i = -cos_theta(dir_in);
+		let cos_o = cos_theta(dir_out);
+		if cos_i <= 0.0 || cos_o <= 0.0 {
+			return Vec3::zero();
+		}
+		self.lookup(-dir_in, dir_out) * cos_o
+	}
+
+	fn pdf(&self, dir_in: Vec3, dir_out: Vec3, _tc: TexCoords) -> f32 {
+		if -cos_theta(dir_in) <= 0.0 || cos_theta(dir_out) <= 0.0 {
+			return 0.0;
+		}
+		MEASURED_HALF_PROB * self.pdf_half(-dir_in, dir_out) + (1.0 - MEASURED_HALF_PROB) * cosine_hemisphere_pdf(dir_out)
 	}
 
 	fn is_purely_specular(&self) -> bool {
@@ -628,6 +2100,14 @@ impl Material for SmoothCoat {
 	}
 }
 
+/// Fresnel reflectance of a conductor, possibly coated with a thin film
+fn conductor_reflectance(ior: ComplexIOR, thin_film: &Option<ThinFilm>, tc: TexCoords, cos_i: f32) -> Vec3 {
+	match *thin_film {
+		Some(ref film) => film.reflectance(tc, 1.0, ior, cos_i),
+		None => fresnel::conductor_reflectance_rgb(ior, cos_i),
+	}
+}
+
 fn refract(dir_in: Vec3, eta: f32, cos_t: f32) -> Vec3 {
 	Vec3::new(dir_in.x * eta, cos_t * cos_theta(dir_in).signum(), dir_in.z * eta)
 }
@@ -661,6 +2141,168 @@ mod fresnel {
 		return ((r_s * r_s + r_p * r_p) * 0.5, cos_t);
 	}
 
+	/// Reflectance of a thin film of IOR `eta`, with the same medium on both sides, at the
+	/// given wavelength (in the same unit as the thickness)
+	///
+	/// Accounts for the interferences between the waves reflected inside the film.
+	pub fn thin_film_reflectance(eta: f32, cos_i: f32, thickness: f32, wavelength: f32) -> f32 {
+		let cos_i = cos_i.min(1.0).max(-1.0).abs();
+		let sin_t2 = (1.0 - cos_i * cos_i) / (eta * eta);
+		if sin_t2 > 1.0 {
+			return 1.0;
+		}
+		let cos_t = (1.0 - sin_t2).sqrt();
+
+		// phase difference between two successive reflected waves
+		let cos_delta = (4.0 * PI * eta * thickness * cos_t / wavelength).cos();
+
+		let airy = |r: f32| {
+			let r2 = r * r;
+			2.0 * r2 * (1.0 - cos_delta) / (1.0 + r2 * r2 - 2.0 * r2 * cos_delta)
+		};
+		let r_s = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+		let r_p = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+		(airy(r_s) + airy(r_p)) * 0.5
+	}
+
+	/// Thin film reflectance evaluated at representative wavelengths of the RGB primaries
+	///
+	/// The thickness is given in nanometers.
+	pub fn thin_film_reflectance_rgb(eta: f32, cos_i: f32, thickness: f32) -> Vec3 {
+		Vec3 {
+			x: thin_film_reflectance(eta, cos_i, thickness, 650.0),
+			y: thin_film_reflectance(eta, cos_i, thickness, 510.0),
+			z: thin_film_reflectance(eta, cos_i, thickness, 475.0),
+		}
+	}
+
+	/// Reflectance of a substrate of complex IOR `eta + i k` coated by a thin film of IOR
+	/// `film_eta`, lit from a medium of IOR `outer_eta`, at the given wavelength (in the same
+	/// unit as the thickness)
+	///
+	/// Sums the waves reflected at both interfaces with the Airy formula.
+	pub fn thin_film_coating_reflectance(outer_eta: f32, film_eta: f32, eta: f32, k: f32, cos_i: f32, thickness: f32, wavelength: f32) -> f32 {
+		let cos_i = cos_i.min(1.0).max(-1.0).abs();
+		let sin_i2 = 1.0 - cos_i * cos_i;
+
+		// cosines in the film and in the substrate, complex for evanescent or absorbed waves
+		let n1 = Complex::real(outer_eta);
+		let n2 = Complex::real(film_eta);
+		let n3 = Complex { re: eta, im: k };
+		let cos_t = |n: Complex| (Complex::real(1.0) - (Complex::real(outer_eta * outer_eta * sin_i2) / (n * n))).sqrt();
+		let cos1 = Complex::real(cos_i);
+		let cos2 = cos_t(n2);
+		let cos3 = cos_t(n3);
+
+		let r_s = |na: Complex, ca: Complex, nb: Complex, cb: Complex| (na * ca - nb * cb) / (na * ca + nb * cb);
+		let r_p = |na: Complex, ca: Complex, nb: Complex, cb: Complex| (nb * ca - na * cb) / (nb * ca + na * cb);
+
+		// phase shift accumulated by a round trip inside the film
+		let delta = Complex::real(4.0 * PI * thickness / wavelength) * n2 * cos2;
+		let phase = Complex::exp_i(delta);
+		let airy = |r12: Complex, r23: Complex| {
+			let r = (r12 + r23 * phase) / (Complex::real(1.0) + r12 * r23 * phase);
+			r.norm_sqr().min(1.0)
+		};
+
+		let s = airy(r_s(n1, cos1, n2, cos2), r_s(n2, cos2, n3, cos3));
+		let p = airy(r_p(n1, cos1, n2, cos2), r_p(n2, cos2, n3, cos3));
+		(s + p) * 0.5
+	}
+
+	/// Thin film coating reflectance evaluated at representative wavelengths of the RGB primaries
+	///
+	/// The thickness is given in nanometers.
+	pub fn thin_film_coating_rgb(outer_eta: f32, film_eta: f32, substrate: ComplexIOR, cos_i: f32, thickness: f32) -> Vec3 {
+		let r = |eta: f32, k: f32, wavelength: f32| {
+			thin_film_coating_reflectance(outer_eta, film_eta, eta, k, cos_i, thickness, wavelength)
+		};
+		Vec3 {
+			x: r(substrate.eta.x, substrate.k.x, 650.0),
+			y: r(substrate.eta.y, substrate.k.y, 510.0),
+			z: r(substrate.eta.z, substrate.k.z, 475.0),
+		}
+	}
+
+	#[derive(Clone, Copy)]
+	struct Complex {
+		re: f32,
+		im: f32,
+	}
+
+	impl Complex {
+		fn real(re: f32) -> Complex {
+			Complex { re, im: 0.0 }
+		}
+
+		/// e^(i z)
+		fn exp_i(z: Complex) -> Complex {
+			let m = (-z.im).exp();
+			Complex { re: m * z.re.cos(), im: m * z.re.sin() }
+		}
+
+		fn norm_sqr(self) -> f32 {
+			self.re * self.re + self.im * self.im
+		}
+
+		/// Principal square root
+		fn sqrt(self) -> Complex {
+			let m = self.norm_sqr().sqrt();
+			let re = ((m + self.re) * 0.5).max(0.0).sqrt();
+			let im = ((m - self.re) * 0.5).max(0.0).sqrt();
+			Complex { re, im: if self.im < 0.0 { -im } else { im } }
+		}
+	}
+
+	impl ::std::ops::Add for Complex {
+		type Output = Complex;
+		fn add(self, o: Complex) -> Complex {
+			Complex { re: self.re + o.re, im: self.im + o.im }
+		}
+	}
+
+	impl ::std::ops::Sub for Complex {
+		type Output = Complex;
+		fn sub(self, o: Complex) -> Complex {
+			Complex { re: self.re - o.re, im: self.im - o.im }
+		}
+	}
+
+	impl ::std::ops::Mul for Complex {
+		type Output = Complex;
+		fn mul(self, o: Complex) -> Complex {
+			Complex { re: self.re * o.re - self.im * o.im, im: self.re * o.im + self.im * o.re }
+		}
+	}
+
+	impl ::std::ops::Div for Complex {
+		type Output = Complex;
+		fn div(self, o: Complex) -> Complex {
+			let d = o.norm_sqr();
+			Complex { re: (self.re * o.re + self.im * o.im) / d, im: (self.im * o.re - self.re * o.im) / d }
+		}
+	}
+
+	/// Hemispherical average of the dielectric reflectance, weighted by the cosine
+	pub fn dielectric_average(eta: f32) -> f32 {
+		average(|cos_i| Vec3::thrice(dielectric_reflectance(eta, cos_i).0)).x
+	}
+
+	/// Hemispherical average of the conductor reflectance, weighted by the cosine
+	pub fn conductor_average_rgb(ior: ComplexIOR) -> Vec3 {
+		average(|cos_i| conductor_reflectance_rgb(ior, cos_i))
+	}
+
+	fn average<F: Fn(f32) -> Vec3>(reflectance: F) -> Vec3 {
+		let n = 64;
+		let mut sum = Vec3::zero();
+		for i in 0..n {
+			let cos_i = (i as f32 + 0.5) / n as f32;
+			sum += reflectance(cos_i) * (2.0 * cos_i);
+		}
+		sum / n as f32
+	}
+
 	fn conductor_reflectance(eta: f32, k: f32, cos_i: f32) -> f32 {
 		let cos_i2 = cos_i * cos_i;
 		let sin_i2 = 1.0 - cos_i2;
@@ -688,49 +2330,263 @@ mod fresnel {
 	}
 }
 
+/// Compensation of the energy lost by single scattering microfacet models (Kulla and Conty 2017)
+///
+/// Light scattered several times between microfacets is accounted for by an additional lobe,
+/// computed from the directional albedo of the single scattering model.
+mod multiple_scattering {
+	use std::sync::{OnceLock, RwLock};
+
+	use math::*;
+	use super::{MIN_ALPHA, cos_theta, microfacet};
+
+	/// Resolution of the tables, both in cosine and roughness
+	const SIZE: usize = 16;
+	/// Number of samples used to estimate each albedo
+	const NB_SAMPLES: usize = 1024;
+
+	/// Directional albedo of a microfacet lobe, tabulated by the cosine of the incoming direction
+	/// and the roughness, in [0, 1]
+	pub struct AlbedoTable {
+		albedo: Vec<f32>,
+		average: Vec<f32>,
+	}
+
+	impl AlbedoTable {
+		/// Estimate the albedo from the weights of samples of the lobe
+		///
+		/// `sample_weight` is given a roughness, an incoming direction on the upper side and random numbers.
+		pub fn compute<F>(sample_weight: F) -> AlbedoTable
+			where F: Fn((f32, f32), Vec3, Vec3) -> f32
+		{
+			AlbedoTable::compute_with_max(sample_weight, 1.0)
+		}
+
+		/// Same as `compute`, for lobes whose albedo may exceed one, up to `max`
+		///
+		/// Only the albedo is meaningful for these lobes, not their multiple scattering lobe.
+		pub fn compute_with_max<F>(sample_weight: F, max: f32) -> AlbedoTable
+			where F: Fn((f32, f32), Vec3, Vec3) -> f32
+		{
+			let mut albedo = vec![0.0; SIZE * SIZE];
+			for j in 0..SIZE {
+				let alpha = alpha_at(j);
+				for i in 0..SIZE {
+					let cos_i = cos_at(i);
+					let dir_in = Vec3::new((1.0 - cos_i * cos_i).sqrt(), -cos_i, 0.0);
+
+					// Hammersley point set
+					let mut sum = 0.0;
+					for k in 0..NB_SAMPLES {
+						let u = (k as f32 + 0.5) / NB_SAMPLES as f32;
+						let rnd = Vec3::new(u, radical_inverse(2, k), radical_inverse(3, k));
+						sum += sample_weight((alpha, alpha), dir_in, rnd);
+					}
+					albedo[j * SIZE + i] = (sum / NB_SAMPLES as f32).min(max);
+				}
+			}
+
+			let average = (0..SIZE).map(|j| {
+				let row = &albedo[j * SIZE..(j + 1) * SIZE];
+				row.iter().enumerate().map(|(i, e)| 2.0 * e * cos_at(i)).sum::<f32>() / SIZE as f32
+			}).collect();
+
+			AlbedoTable { albedo, average }
+		}
+
+		pub fn albedo(&self, cos_i: f32, alpha: f32) -> f32 {
+			let x = (cos_i.abs() * SIZE as f32 - 0.5).max(0.0).min((SIZE - 1) as f32);
+			let (j0, j1, ty) = alpha_coords(alpha);
+			let i0 = x.floor() as usize;
+			let i1 = (i0 + 1).min(SIZE - 1);
+			let tx = x - i0 as f32;
+
+			let get = |i, j| self.albedo[j * SIZE + i];
+			let e0 = get(i0, j0) * (1.0 - tx) + get(i1, j0) * tx;
+			let e1 = get(i0, j1) * (1.0 - tx) + get(i1, j1) * tx;
+			e0 * (1.0 - ty) + e1 * ty
+		}
+
+		/// Hemispherical average of the albedo, weighted by the cosine
+		pub fn average(&self, alpha: f32) -> f32 {
+			let (j0, j1, t) = alpha_coords(alpha);
+			self.average[j0] * (1.0 - t) + self.average[j1] * t
+		}
+
+		/// Value of the lobe of multiply scattered light, times the cosine of the outgoing direction
+		///
+		/// This lobe is energy conserving when the microfacets reflect all light.
+		pub fn eval(&self, alpha: f32, cos_i: f32, cos_o: f32) -> f32 {
+			let e_avg = self.average(alpha);
+			if e_avg >= 1.0 {
+				return 0.0;
+			}
+			(1.0 - self.albedo(cos_i, alpha)) * (1.0 - self.albedo(cos_o, alpha)) * cos_o.abs() / (PI * (1.0 - e_avg))
+		}
+	}
+
+	fn radical_inverse(base: usize, mut k: usize) -> f32 {
+		let inv_base = 1.0 / base as f32;
+		let mut factor = inv_base;
+		let mut x = 0.0;
+		while k > 0 {
+			x += (k % base) as f32 * factor;
+			k /= base;
+			factor *= inv_base;
+		}
+		x
+	}
+
+	fn cos_at(i: usize) -> f32 {
+		(i as f32 + 0.5) / SIZE as f32
+	}
+
+	fn alpha_at(j: usize) -> f32 {
+		(j as f32 / (SIZE - 1) as f32).max(MIN_ALPHA)
+	}
+
+	fn alpha_coords(alpha: f32) -> (usize, usize, f32) {
+		let y = (alpha * (SIZE - 1) as f32).max(0.0).min((SIZE - 1) as f32);
+		let j0 = y.floor() as usize;
+		(j0, (j0 + 1).min(SIZE - 1), y - j0 as f32)
+	}
+
+	/// Values computed once per distinct key and kept for the rest of the program, so that
+	/// materials only need their parameters
+	pub struct Cache<K: 'static, V: 'static> {
+		entries: RwLock<Vec<(K, &'static V)>>,
+	}
+
+	impl<K: Copy + PartialEq, V> Cache<K, V> {
+		pub const fn new() -> Cache<K, V> {
+			Cache { entries: RwLock::new(Vec::new()) }
+		}
+
+		pub fn get<F: FnOnce() -> V>(&self, key: K, compute: F) -> &'static V {
+			if let Some(&(_, value)) = self.entries.read().unwrap().iter().find(|e| e.0 == key) {
+				return value;
+			}
+			let mut entries = self.entries.write().unwrap();
+			// another thread may have computed it in the meantime
+			if let Some(&(_, value)) = entries.iter().find(|e| e.0 == key) {
+				return value;
+			}
+			let value: &'static V = Box::leak(Box::new(compute()));
+			entries.push((key, value));
+			value
+		}
+	}
+
+	/// Isotropic roughness used to look up anisotropic ones
+	pub fn table_alpha((alpha_u, alpha_v): (f32, f32)) -> f32 {
+		(alpha_u * alpha_v).sqrt()
+	}
+
+	/// Albedo of a GGX reflection lobe with a unit Fresnel reflectance
+	pub fn ggx_albedo() -> &'static AlbedoTable {
+		static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+		TABLE.get_or_init(|| AlbedoTable::compute(|alpha, dir_in, rnd| {
+			let h = microfacet::sample_visible(alpha, -dir_in, (rnd.x, rnd.y));
+			let dir_out = 2.0 * -Vec3::dot(dir_in, h) * h + dir_in;
+			if cos_theta(dir_out) <= 0.0 {
+				return 0.0;
+			}
+			microfacet::shadowing_1d(alpha, dir_out, h)
+		}))
+	}
+
+	/// Albedo of a GGX reflection lobe with Schlick's Fresnel weight (1 - cos)^5
+	///
+	/// By linearity, the albedo for a reflectance `f0` at normal incidence is
+	/// `f0 * ggx_albedo + (1 - f0) * ggx_schlick_albedo`.
+	pub fn ggx_schlick_albedo() -> &'static AlbedoTable {
+		static TABLE: OnceLock<AlbedoTable> = OnceLock::new();
+		TABLE.get_or_init(|| AlbedoTable::compute(|alpha, dir_in, rnd| {
+			let h = microfacet::sample_visible(alpha, -dir_in, (rnd.x, rnd.y));
+			let in_dot_h = -Vec3::dot(dir_in, h);
+			let dir_out = 2.0 * in_dot_h * h + dir_in;
+			if cos_theta(dir_out) <= 0.0 {
+				return 0.0;
+			}
+			schlick_weight(in_dot_h) * microfacet::shadowing_1d(alpha, dir_out, h)
+		}))
+	}
+
+	/// Weight of the reflectance at grazing angles in Schlick's approximation of the Fresnel factor
+	pub fn schlick_weight(cos: f32) -> f32 {
+		(1.0 - cos).max(0.0).min(1.0).powi(5)
+	}
+
+	/// Color of the multiple scattering lobe, given the average Fresnel reflectance
+	pub fn fresnel_tint(f_avg: Vec3, e_avg: f32) -> Vec3 {
+		f_avg.map(|f| f * f * e_avg / (1.0 - f * (1.0 - e_avg)))
+	}
+}
+
+/// Anisotropic GGX microfacet distribution
+///
+/// Roughnesses are given as `(alpha_u, alpha_v)`, along the X and Z axes of the shading frame.
 mod microfacet {
 	use math::*;
 	use super::cos_theta;
 
-	pub fn distribution(alpha: f32, cos_theta: f32) -> f32 {
-		if cos_theta <= 0.0 {
+	pub fn distribution((alpha_u, alpha_v): (f32, f32), h: Vec3) -> f32 {
+		if cos_theta(h) <= 0.0 {
 			return 0.0;
 		}
 
-		let alpha2 = alpha * alpha;
-		let cos_theta2 = cos_theta * cos_theta;
-		let tan_theta2 = (1.0 - cos_theta2) / cos_theta2;
-		let cos_theta4 = cos_theta2 * cos_theta2;
-		let x = alpha2 + tan_theta2;
-		alpha2 / (PI * cos_theta4 * x * x)
+		let x = h.x * h.x / (alpha_u * alpha_u) + h.z * h.z / (alpha_v * alpha_v) + h.y * h.y;
+		1.0 / (PI * alpha_u * alpha_v * x * x)
 	}
 
-	fn shadowing_1d(alpha: f32, v: Vec3, h: Vec3) -> f32 {
+	pub fn shadowing_1d((alpha_u, alpha_v): (f32, f32), v: Vec3, h: Vec3) -> f32 {
 		let cos_theta = cos_theta(v);
 		if Vec3::dot(v, h) * cos_theta <= 0.0 {
 			return 0.0;
 		}
 
-		let alpha2 = alpha * alpha;
-		let cos_theta2 = cos_theta * cos_theta;
-		let tan_theta2 = (1.0 - cos_theta2) / cos_theta2;
-		2.0 / (1.0 + (1.0 + alpha2 * tan_theta2).sqrt())
+		// squared roughness in the azimuthal direction of v, times tan^2(theta)
+		let alpha2_tan_theta2 = (v.x * v.x * alpha_u * alpha_u + v.z * v.z * alpha_v * alpha_v) / (cos_theta * cos_theta);
+		2.0 / (1.0 + (1.0 + alpha2_tan_theta2).sqrt())
 	}
 
-	pub fn shadowing(alpha: f32, dir_in: Vec3, dir_out: Vec3, h: Vec3) -> f32 {
+	pub fn shadowing(alpha: (f32, f32), dir_in: Vec3, dir_out: Vec3, h: Vec3) -> f32 {
 		shadowing_1d(alpha, -dir_in, h) * shadowing_1d(alpha, dir_out, h)
 	}
 
-	pub fn sample(alpha: f32, (u, v): (f32, f32)) -> (Vec3, f32) {
-		let phi = v * 2.0 * PI;
-		let tan_theta2 = alpha * alpha * u / (1.0 - u);
-		let cos_theta = 1.0 / (1.0 + tan_theta2).sqrt();
-		let r = (1.0 - cos_theta * cos_theta).sqrt();
-		(Vec3::new(phi.cos() * r, cos_theta, phi.sin() * r), distribution(alpha, cos_theta) * cos_theta)
+	/// Sample a microfacet normal visible from direction `v` (Heitz 2018)
+	pub fn sample_visible((alpha_u, alpha_v): (f32, f32), v: Vec3, (u1, u2): (f32, f32)) -> Vec3 {
+		// work in a Z-up frame, as in the paper
+		let vh = Vec3::new(alpha_u * v.x, alpha_v * v.z, v.y).normalized();
+
+		// orthonormal basis around vh
+		let len2 = vh.x * vh.x + vh.y * vh.y;
+		let t1 = if len2 > 0.0 {
+			Vec3::new(-vh.y, vh.x, 0.0) / len2.sqrt()
+		} else {
+			Vec3::new(1.0, 0.0, 0.0)
+		};
+		let t2 = Vec3::cross(vh, t1);
+
+		// sample the projected area of the visible hemisphere
+		let r = u1.sqrt();
+		let phi = 2.0 * PI * u2;
+		let p1 = r * phi.cos();
+		let s = 0.5 * (1.0 + vh.z);
+		let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+		let nh = t1 * p1 + t2 * p2 + vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+
+		// unstretch and go back to the Y-up frame
+		Vec3::new(alpha_u * nh.x, nh.z.max(0.0), alpha_v * nh.y).normalized()
 	}
 
-	pub fn pdf(alpha: f32, cos_theta: f32) -> f32 {
-		distribution(alpha, cos_theta) * cos_theta
+	/// Density of sampling `h` with `sample_visible`
+	pub fn pdf_visible(alpha: (f32, f32), v: Vec3, h: Vec3) -> f32 {
+		let cos_v = cos_theta(v);
+		if cos_v <= 0.0 {
+			return 0.0;
+		}
+		shadowing_1d(alpha, v, h) * Vec3::dot(v, h).max(0.0) * distribution(alpha, h) / cos_v
 	}
 }
 
@@ -778,3 +2634,398 @@ pub const CONDUCTORS_IOR: [(&'static str, ComplexIOR); 40] = [
 	("V",      ComplexIOR { eta: Vec3 { x: 4.2775126218, y: 3.5131538236, z: 2.7611257461 }, k: Vec3 { x: 3.4911844504, y: 2.8893580874, z: 3.1116965117 } }),
 	("W",      ComplexIOR { eta: Vec3 { x: 4.3707029924, y: 3.3002972445, z: 2.9982666528 }, k: Vec3 { x: 3.5006778591, y: 2.6048652781, z: 2.2731930614 } }),
 ];
+
+#[test]
+fn test_white_furnace() {
+	use rand::{Rng, SeedableRng, XorShiftRng};
+
+	let white = || Texture::Constant(Vec3::thrice(1.0));
+	let perfect_conductor = ComplexIOR { eta: Vec3::zero(), k: Vec3::thrice(1.0) };
+
+	let mut rng = XorShiftRng::from_seed([0, 1, 2, 3]);
+	for &roughness in &[0.2, 0.6, 1.0] {
+		let materials: Vec<(&str, Box<Material>)> = vec![
+			("conductor", Box::new(RoughConductor { albedo: white(), ior: perfect_conductor, roughness: Roughness::Isotropic(Texture::Constant(Vec3::thrice(roughness))), thin_film: None })),
+			("plastic", Box::new(RoughPlastic::new(white(), 1.5, Roughness::Isotropic(Texture::Constant(Vec3::thrice(roughness)))))),
+			("dielectric", Box::new(RoughDielectric { albedo: white(), ior: DielectricIOR::Constant(1.5), roughness: Roughness::Isotropic(Texture::Constant(Vec3::thrice(roughness))) })),
+		];
+
+		for &(name, ref material) in &materials {
+			for &cos_i in &[0.2f32, 0.6, 0.95] {
+				// from the outside, and from the inside for dielectrics
+				for &side in &[1.0, -1.0] {
+					if side < 0.0 && name != "dielectric" {
+						continue;
+					}
+					let dir_in = Vec3::new((1.0 - cos_i * cos_i).sqrt(), -cos_i * side, 0.0);
+
+					let n = 20000;
+					let mut albedo = 0.0;
+					for _ in 0..n {
+						let rnd = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+						albedo += material.sample(dir_in, TexCoords::default(), rnd).weight.avg();
+					}
+					albedo /= n as f32;
+
+					assert!((albedo - 1.0).abs() < 0.03,
+						"{} with roughness {} loses energy: albedo {} at cos {}", name, roughness, albedo, cos_i * side);
+				}
+			}
+		}
+	}
+}
+
+#[test]
+fn test_measured_lookup() {
+	let (res_h, res_d, res_phi) = MERL_RES;
+	// each entry holds its own index
+	let measured = Measured::from_table((0..res_h * res_d * res_phi).map(|i| Vec3::thrice(i as f32)).collect());
+
+	for &(ih, id, iphi, phi_h) in &[(40, 30, 50, 2.0f32), (10, 60, 150, -1.2), (70, 5, 100, 0.4)] {
+		// angles at the center of the entry
+		let u = (ih as f32 + 0.5) / res_h as f32;
+		let theta_h = u * u * FRAC_PI_2;
+		let theta_d = (id as f32 + 0.5) / res_d as f32 * FRAC_PI_2;
+		let phi_d = (iphi as f32 + 0.5) / res_phi as f32 * PI;
+
+		// undo the rotations of `lookup`: by theta_h around the bitangent, then -phi_h around the normal
+		let d = Vec3::new(theta_d.sin() * phi_d.sin(), theta_d.cos(), theta_d.sin() * phi_d.cos());
+		let (s, c) = theta_h.sin_cos();
+		let t = Vec3::new(d.x, d.y * c - d.z * s, d.y * s + d.z * c);
+		let (s, c) = (-phi_h).sin_cos();
+		let wi = Vec3::new(t.x * c - t.z * s, t.y, t.x * s + t.z * c);
+		let h = Vec3::new(theta_h.sin() * phi_h.sin(), theta_h.cos(), theta_h.sin() * phi_h.cos());
+		let wo = h * (2.0 * Vec3::dot(wi, h)) - wi;
+
+		let expected = ((ih * res_d + id) * res_phi + iphi) as f32;
+		assert_eq!(measured.lookup(wi, wo).x, expected, "entry ({}, {}, {}) with phi_h {}", ih, id, iphi, phi_h);
+		// swapping the directions turns phi_d by pi, which is the same entry for isotropic BRDFs
+		assert_eq!(measured.lookup(wo, wi).x, expected, "swapped entry ({}, {}, {})", ih, id, iphi);
+	}
+}
+
+/// Material exercised by the statistical tests of BSDFs
+#[cfg(test)]
+struct TestMaterial {
+	name: String,
+	material: Arc<Material>,
+	/// Whether light may also arrive from below the surface
+	transmissive: bool,
+	/// Whether the BSDF is symmetric, which approximate layered models are not
+	reciprocal: bool,
+}
+
+/// All the materials, with white albedos and a range of roughnesses
+#[cfg(test)]
+fn test_materials() -> Vec<TestMaterial> {
+	let constant = |v: f32| Texture::Constant(Vec3::thrice(v));
+	let white = || constant(1.0);
+	let diffuse = || -> Arc<Material> { Arc::new(Diffuse { albedo: white() }) };
+	let gold = ComplexIOR::from_symbol("Au").unwrap();
+	let perfect_conductor = ComplexIOR { eta: Vec3::zero(), k: Vec3::thrice(1.0) };
+
+	let mut materials = Vec::new();
+	{
+		let mut add = |name: String, material: Arc<Material>, transmissive: bool, reciprocal: bool| {
+			materials.push(TestMaterial { name, material, transmissive, reciprocal });
+		};
+
+		add("diffuse".to_owned(), diffuse(), false, true);
+		add("null".to_owned(), Arc::new(Null), true, true);
+		add("mirror".to_owned(), Arc::new(Mirror { albedo: white() }), false, true);
+		add("conductor".to_owned(), Arc::new(Conductor { albedo: white(), ior: gold, thin_film: None }), false, true);
+		add("dielectric".to_owned(), Arc::new(Dielectric { albedo: white(), ior: DielectricIOR::Constant(1.5), thin_film: None }), true, false);
+		add("thin dielectric".to_owned(), Arc::new(ThinDielectric { albedo: white(), ior: 1.5, scaled_sigma_a: Vec3::zero(), film_thickness: None }), true, true);
+		add("plastic".to_owned(), Arc::new(Plastic::new(white(), 1.5)), false, false);
+		add("smooth coat".to_owned(), Arc::new(SmoothCoat { ior: 1.5, scaled_sigma_a: Vec3::thrice(0.1), substrate: diffuse() }), false, false);
+		add("transparency".to_owned(), Arc::new(Transparency { opacity: constant(0.5), base: diffuse() }), false, true);
+		add("emissive".to_owned(), Arc::new(Emissive { emission: white(), base: diffuse() }), false, true);
+		add("normal mapped".to_owned(), Arc::new(NormalMapped { normal_map: NormalMap::Tangent(constant(0.7)), base: diffuse() }), false, true);
+		add("two-sided diffuse".to_owned(), Arc::new(TwoSided { base: diffuse() }), true, true);
+
+		// lobe around the normal on top of a constant, only depending on theta_half
+		let (res_h, res_d, res_phi) = MERL_RES;
+		let measured = Measured::from_table((0..res_h * res_d * res_phi).map(|i| {
+			let u = ((i / (res_d * res_phi)) as f32 + 0.5) / res_h as f32;
+			let theta_h = u * u * FRAC_PI_2;
+			Vec3::thrice(0.05 + 0.5 * (-(theta_h / 0.25).powi(2)).exp())
+		}).collect());
+		add("measured".to_owned(), Arc::new(measured), false, true);
+
+		for &r in &[0.2, 0.5, 0.9] {
+			let iso = || Roughness::Isotropic(constant(r));
+			add(format!("oren-nayar {}", r), Arc::new(OrenNayar { albedo: white(), sigma: constant(r) }), false, true);
+			add(format!("sheen {}", r), Arc::new(Sheen { albedo: white(), roughness: constant(r) }), false, true);
+			add(format!("rough conductor {}", r), Arc::new(RoughConductor { albedo: white(), ior: gold, roughness: iso(), thin_film: None }), false, true);
+			let anisotropic = || RoughConductor { albedo: white(), ior: perfect_conductor, roughness: Roughness::Anisotropic(constant(r), constant(0.5 * r)), thin_film: None };
+			add(format!("anisotropic rough conductor {}", r), Arc::new(anisotropic()), false, true);
+			let coated = RoughConductor { albedo: white(), ior: gold, roughness: iso(), thin_film: Some(ThinFilm { thickness: constant(300.0), ior: 1.5 }) };
+			add(format!("coated rough conductor {}", r), Arc::new(coated), false, true);
+			add(format!("rough plastic {}", r), Arc::new(RoughPlastic::new(white(), 1.5, iso())), false, false);
+			add(format!("rough dielectric {}", r), Arc::new(RoughDielectric { albedo: white(), ior: DielectricIOR::Constant(1.5), roughness: iso() }), true, false);
+			add(format!("rough coat {}", r), Arc::new(RoughCoat { ior: 1.5, scaled_sigma_a: Vec3::thrice(0.1), roughness: iso(), substrate: diffuse() }), false, false);
+			add(format!("principled {}", r), Arc::new(Principled {
+				base_color: white(),
+				metallic: constant(0.5),
+				roughness: constant(r),
+				specular: constant(0.5),
+				sheen: constant(0.5),
+				clearcoat: constant(0.5),
+				transmission: constant(0.0),
+				ior: 1.5,
+			}), false, false);
+			add(format!("mix {}", r), Arc::new(Mix { weight: constant(0.3), first: diffuse(), second: Arc::new(RoughConductor { albedo: white(), ior: gold, roughness: iso(), thin_film: None }) }), false, true);
+			add(format!("two-sided rough conductor {}", r), Arc::new(TwoSided { base: Arc::new(anisotropic()) }), true, true);
+		}
+	}
+	materials
+}
+
+/// Incident directions (pointing toward the surface) at various elevations and azimuths,
+/// also from below the surface if `transmissive`
+#[cfg(test)]
+fn test_directions(transmissive: bool) -> Vec<Vec3> {
+	let mut dirs = Vec::new();
+	for (i, &cos_i) in [0.95f32, 0.6, 0.2].iter().enumerate() {
+		let sin_i = (1.0 - cos_i * cos_i).sqrt();
+		let phi = 0.3 + 2.1 * i as f32;
+		let dir = Vec3::new(sin_i * phi.cos(), -cos_i, sin_i * phi.sin());
+		dirs.push(dir);
+		if transmissive {
+			dirs.push(Vec3::new(dir.x, -dir.y, dir.z));
+		}
+	}
+	dirs
+}
+
+/// Natural logarithm of the gamma function (Lanczos approximation)
+#[cfg(test)]
+fn ln_gamma(x: f64) -> f64 {
+	let coefs = [76.18009172947146, -86.50532032941677, 24.01409824083091, -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5];
+	let tmp = x + 5.5;
+	let mut y = x;
+	let mut series = 1.000000000190015;
+	for c in &coefs {
+		y += 1.0;
+		series += c / y;
+	}
+	(x + 0.5) * tmp.ln() - tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// Regularized upper incomplete gamma function Q(a, x), which gives the p-value of a
+/// chi-square statistic x with 2a degrees of freedom
+#[cfg(test)]
+fn gamma_q(a: f64, x: f64) -> f64 {
+	if x <= 0.0 {
+		return 1.0;
+	}
+	let prefactor = (a * x.ln() - x - ln_gamma(a)).exp();
+	if x < a + 1.0 {
+		// series of the lower function P(a, x)
+		let mut term = 1.0 / a;
+		let mut sum = term;
+		let mut n = a;
+		while term > sum * 1e-14 {
+			n += 1.0;
+			term *= x / n;
+			sum += term;
+		}
+		1.0 - prefactor * sum
+	} else {
+		// continued fraction, with the modified Lentz method
+		const TINY: f64 = 1e-300;
+		let mut b = x + 1.0 - a;
+		let mut c = 1.0 / TINY;
+		let mut d = 1.0 / b;
+		let mut h = d;
+		for i in 1..10000 {
+			let an = -(i as f64) * (i as f64 - a);
+			b += 2.0;
+			d = an * d + b;
+			if d.abs() < TINY { d = TINY; }
+			c = b + an / c;
+			if c.abs() < TINY { c = TINY; }
+			d = 1.0 / d;
+			let delta = d * c;
+			h *= delta;
+			if (delta - 1.0).abs() < 1e-14 {
+				break;
+			}
+		}
+		prefactor * h
+	}
+}
+
+/// Chi-square goodness-of-fit test of the directions sampled by `material` against its pdf,
+/// returning the p-value of the hypothesis that they follow it
+///
+/// The sphere is divided in cells of equal solid angle in (cos theta, phi), where the pdf is
+/// integrated numerically. Specular samples are ignored.
+#[cfg(test)]
+fn chi_square_pvalue<R: ::rand::Rng>(material: &Material, dir_in: Vec3, rng: &mut R) -> f64 {
+	const RES_COS: usize = 16;
+	const RES_PHI: usize = 32;
+	const SUB: usize = 16;
+	const NB_SAMPLES: usize = 100000;
+
+	let cell = |d: Vec3| {
+		let c = (((d.y + 1.0) * 0.5 * RES_COS as f32) as usize).min(RES_COS - 1);
+		let phi = d.z.atan2(d.x) + PI;
+		let p = ((phi / (2.0 * PI) * RES_PHI as f32) as usize).min(RES_PHI - 1);
+		c * RES_PHI + p
+	};
+
+	let mut observed = vec![0.0f64; RES_COS * RES_PHI];
+	for _ in 0..NB_SAMPLES {
+		let s = material.sample(dir_in, TexCoords::default(), Vec3::new(rng.gen(), rng.gen(), rng.gen()));
+		if !s.is_specular && s.pdf > 0.0 && s.weight != Vec3::zero() {
+			observed[cell(s.direction.normalized())] += 1.0;
+		}
+	}
+
+	let (d_cos, d_phi) = (2.0 / RES_COS as f32, 2.0 * PI / RES_PHI as f32);
+	let mut cells: Vec<(f64, f64)> = observed.iter().enumerate().map(|(i, &obs)| {
+		let (c, p) = (i / RES_PHI, i % RES_PHI);
+		let mut integral = 0.0;
+		for j in 0..SUB * SUB {
+			let cos = -1.0 + (c as f32 + ((j / SUB) as f32 + 0.5) / SUB as f32) * d_cos;
+			let phi = -PI + (p as f32 + ((j % SUB) as f32 + 0.5) / SUB as f32) * d_phi;
+			let sin = (1.0 - cos * cos).max(0.0).sqrt();
+			let dir_out = Vec3::new(sin * phi.cos(), cos, sin * phi.sin());
+			integral += material.pdf(dir_in, dir_out, TexCoords::default()) as f64;
+		}
+		let expected = integral * (d_cos * d_phi) as f64 / (SUB * SUB) as f64 * NB_SAMPLES as f64;
+		(expected, obs)
+	}).collect();
+
+	// pool the cells with low expected counts, for which the statistic is not reliable
+	cells.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+	let (mut pooled_expected, mut pooled_observed) = (0.0, 0.0);
+	let mut chi2 = 0.0;
+	let mut nb_cells = 0;
+	for &(expected, observed) in &cells {
+		if expected < 5.0 {
+			pooled_expected += expected;
+			pooled_observed += observed;
+		} else {
+			chi2 += (observed - expected) * (observed - expected) / expected;
+			nb_cells += 1;
+		}
+	}
+	if pooled_expected > 0.0 {
+		chi2 += (pooled_observed - pooled_expected) * (pooled_observed - pooled_expected) / pooled_expected;
+		nb_cells += 1;
+	} else if pooled_observed > 0.0 {
+		// samples where the pdf is zero
+		return 0.0;
+	}
+	if nb_cells < 2 {
+		// (almost) only specular samples
+		return 1.0;
+	}
+	gamma_q((nb_cells - 1) as f64 / 2.0, chi2 / 2.0)
+}
+
+#[test]
+fn test_bsdf_sampling_matches_pdf() {
+	use rand::{SeedableRng, XorShiftRng};
+
+	let mut rng = XorShiftRng::from_seed([0, 1, 2, 3]);
+	let materials = test_materials();
+	let nb_tests: usize = materials.iter().map(|m| test_directions(m.transmissive).len()).sum();
+	// significance level of 1%, corrected for the number of tests
+	let threshold = 1.0 - 0.99f64.powf(1.0 / nb_tests as f64);
+
+	let mut failures = Vec::new();
+	for m in &materials {
+		for dir_in in test_directions(m.transmissive) {
+			let pvalue = chi_square_pvalue(&*m.material, dir_in, &mut rng);
+			if pvalue < threshold {
+				failures.push(format!("{} at cos {}: p-value {:e}", m.name, -dir_in.y, pvalue));
+			}
+		}
+	}
+	assert!(failures.is_empty(), "sampling does not match the pdf:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_bsdf_sample_weight() {
+	use rand::{Rng, SeedableRng, XorShiftRng};
+
+	let mut rng = XorShiftRng::from_seed([4, 5, 6, 7]);
+	let close = |a: f32, b: f32| (a - b).abs() <= 1e-3 * a.abs().max(b.abs()) + 1e-5;
+
+	let mut failures = Vec::new();
+	for m in &test_materials() {
+		for dir_in in test_directions(m.transmissive) {
+			for _ in 0..2000 {
+				let s = m.material.sample(dir_in, TexCoords::default(), Vec3::new(rng.gen(), rng.gen(), rng.gen()));
+				if s.is_specular || s.pdf <= 0.0 || s.weight == Vec3::zero() {
+					continue;
+				}
+				let pdf = m.material.pdf(dir_in, s.direction, TexCoords::default());
+				let weight = m.material.eval(dir_in, s.direction, TexCoords::default()) / pdf;
+				if !close(pdf, s.pdf) || !(close(weight.x, s.weight.x) && close(weight.y, s.weight.y) && close(weight.z, s.weight.z)) {
+					failures.push(format!("{} at cos {}: sampled weight {:?} and pdf {}, evaluated {:?} and {}",
+						m.name, -dir_in.y, s.weight, s.pdf, weight, pdf));
+					break;
+				}
+			}
+		}
+	}
+	assert!(failures.is_empty(), "sampled weight is not eval / pdf:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_bsdf_reciprocity() {
+	use rand::{Rng, SeedableRng, XorShiftRng};
+
+	let mut rng = XorShiftRng::from_seed([8, 9, 10, 11]);
+	let mut failures = Vec::new();
+	for m in test_materials().iter().filter(|m| m.reciprocal) {
+		for _ in 0..2000 {
+			// directions pointing away from the surface
+			let wi = uniform_hemisphere((rng.gen(), rng.gen()));
+			let wo = uniform_hemisphere((rng.gen(), rng.gen()));
+			if wi.y < 0.05 || wo.y < 0.05 {
+				continue;
+			}
+			// `eval` includes the cosine with the outgoing direction
+			let f_io = m.material.eval(-wi, wo, TexCoords::default()) / wo.y;
+			let f_oi = m.material.eval(-wo, wi, TexCoords::default()) / wi.y;
+			if (f_io - f_oi).max_elem().max((f_oi - f_io).max_elem()) > 1e-3 * f_io.max_elem().max(f_oi.max_elem()) + 1e-5 {
+				failures.push(format!("{}: {:?} from {:?} to {:?}, but {:?} in reverse", m.name, f_io, wi, wo, f_oi));
+				break;
+			}
+		}
+	}
+	assert!(failures.is_empty(), "BSDFs are not reciprocal:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_bsdf_energy_conservation() {
+	use rand::{Rng, SeedableRng, XorShiftRng};
+
+	let mut rng = XorShiftRng::from_seed([12, 13, 14, 15]);
+	let mut failures = Vec::new();
+	for m in test_materials() {
+		for dir_in in test_directions(m.transmissive) {
+			let n = 20000;
+			let mut sum = Vec3::zero();
+			let mut sum_sq = Vec3::zero();
+			for _ in 0..n {
+				let w = m.material.sample(dir_in, TexCoords::default(), Vec3::new(rng.gen(), rng.gen(), rng.gen())).weight;
+				sum += w;
+				sum_sq += w * w;
+			}
+			let albedo = sum / n as f32;
+			let std_error = (sum_sq / n as f32 - albedo * albedo).map(|v| (v.max(0.0) / n as f32).sqrt());
+			// allow for the noise of the estimate
+			let excess = albedo - std_error * 4.0 - Vec3::thrice(1.01);
+			if excess.max_elem() > 0.0 {
+				failures.push(format!("{} at cos {}: albedo {:?}", m.name, -dir_in.y, albedo));
+			}
+		}
+	}
+	assert!(failures.is_empty(), "BSDFs create energy:\n{}", failures.join("\n"));
+}
@@ -14,6 +14,20 @@ impl Frame {
 		Frame(tangent, normal, bitangent)
 	}
 
+	/// Frame whose X axis is aligned with the projection of `tangent` on the plane orthogonal to `normal`
+	///
+	/// Falls back on an arbitrary tangent if `tangent` is (nearly) parallel to the normal.
+	pub fn from_up_tangent(normal: Vec3, tangent: Vec3) -> Frame {
+		let tangent = tangent - normal * Vec3::dot(normal, tangent);
+		let length = tangent.length();
+		if !(length > 1e-6) {
+			return Frame::from_up(normal);
+		}
+		let tangent = tangent / length;
+		let bitangent = Vec3::cross(normal, tangent);
+		Frame(tangent, normal, bitangent)
+	}
+
 	#[inline(always)]
 	pub fn to_world(&self, v: Vec3) -> Vec3 {
 		self.0 * v.x + self.1 * v.y + self.2 * v.z
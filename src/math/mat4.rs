@@ -53,6 +53,39 @@ impl Mat4 {
 		Mat4(inv)
 	}
 
+	pub fn transpose(&self) -> Mat4 {
+		let mut t = Mat4::zero();
+		for i in 0..4 {
+			for j in 0..4 {
+				t[(i, j)] = self[(j, i)];
+			}
+		}
+		t
+	}
+
+	/// Extract the rotation part of an affine transform, discarding translation and scale
+	///
+	/// The basis vectors are orthonormalized with Gram-Schmidt; a mirroring
+	/// transform (negative determinant) is kept as such.
+	pub fn rotation(&self) -> Mat4 {
+		let x = self.transform_vector(Vec3::new(1.0, 0.0, 0.0)).normalized();
+		let y = self.transform_vector(Vec3::new(0.0, 1.0, 0.0));
+		let z = self.transform_vector(Vec3::new(0.0, 0.0, 1.0));
+		let y = (y - x * Vec3::dot(x, y)).normalized();
+		let mut z_ortho = Vec3::cross(x, y);
+		if Vec3::dot(z_ortho, z) < 0.0 {
+			z_ortho = -z_ortho;
+		}
+		let z = z_ortho;
+
+		Mat4([
+			x.x, y.x, z.x, 0.0,
+			x.y, y.y, z.y, 0.0,
+			x.z, y.z, z.z, 0.0,
+			0.0, 0.0, 0.0, 1.0,
+		])
+	}
+
 	pub fn scale(v: Vec3) -> Mat4 {
 		Mat4([
 			v.x, 0.0, 0.0, 0.0,
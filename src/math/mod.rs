@@ -7,7 +7,7 @@ pub mod aabb;
 pub use vec3::Vec3;
 pub use mat4::Mat4;
 pub use frame::Frame;
-pub use ray::Ray;
+pub use ray::{Ray, RayDifferentials};
 pub use aabb::AABB;
 pub use std::f32::{INFINITY, NEG_INFINITY};
 pub use std::f32::consts::*;
@@ -11,3 +11,130 @@ impl Ray {
 		self.origin + self.direction * t
 	}
 }
+
+/// Derivatives of the origin and direction of a ray with respect to the image position, in
+/// pixels, which track the footprint of the ray on the surfaces it hits (Igehy 1999)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RayDifferentials {
+	pub dp_dx: Vec3,
+	pub dp_dy: Vec3,
+	pub dd_dx: Vec3,
+	pub dd_dy: Vec3,
+}
+
+impl RayDifferentials {
+	/// Differentials at the point hit at `distance` along `ray`, on a surface of normal `normal`
+	pub fn transfer(&self, ray: Ray, distance: f32, normal: Vec3) -> RayDifferentials {
+		let dn = Vec3::dot(ray.direction, normal);
+		let transfer = |dp: Vec3, dd: Vec3| {
+			let dp = dp + dd * distance;
+			// stay on the tangent plane of the surface
+			dp - ray.direction * (Vec3::dot(dp, normal) / dn)
+		};
+		RayDifferentials {
+			dp_dx: transfer(self.dp_dx, self.dd_dx),
+			dp_dy: transfer(self.dp_dy, self.dd_dy),
+			..*self
+		}
+	}
+
+	/// Differentials of the mirror reflection of `dir` around `normal`, given the derivatives
+	/// of the normal
+	pub fn reflect(&self, dir: Vec3, normal: Vec3, dn_dx: Vec3, dn_dy: Vec3) -> RayDifferentials {
+		let dn = Vec3::dot(dir, normal);
+		let reflect = |dd: Vec3, dnormal: Vec3| {
+			let ddn = Vec3::dot(dd, normal) + Vec3::dot(dir, dnormal);
+			dd - (dnormal * dn + normal * ddn) * 2.0
+		};
+		RayDifferentials {
+			dd_dx: reflect(self.dd_dx, dn_dx),
+			dd_dy: reflect(self.dd_dy, dn_dy),
+			..*self
+		}
+	}
+
+	/// Differentials of `dir` refracted into `dir_out` through a surface of normal `normal`,
+	/// with `eta` the ratio of the indices of refraction on the incident and transmitted sides
+	pub fn refract(&self, dir: Vec3, dir_out: Vec3, eta: f32, normal: Vec3, dn_dx: Vec3, dn_dy: Vec3) -> RayDifferentials {
+		let dn = Vec3::dot(dir, normal);
+		let mu = eta * dn - Vec3::dot(dir_out, normal);
+		let dmu_ddn = eta - eta * eta * dn / Vec3::dot(dir_out, normal);
+		let refract = |dd: Vec3, dnormal: Vec3| {
+			let ddn = Vec3::dot(dd, normal) + Vec3::dot(dir, dnormal);
+			dd * eta - (normal * (dmu_ddn * ddn) + dnormal * mu)
+		};
+		RayDifferentials {
+			dd_dx: refract(self.dd_dx, dn_dx),
+			dd_dy: refract(self.dd_dy, dn_dy),
+			..*self
+		}
+	}
+}
+
+/// Check that `analytic` is the derivative of `f` at 0 in the direction of a small step
+#[cfg(test)]
+fn assert_derivative<F: Fn(f32) -> Vec3>(f: F, analytic: Vec3) {
+	let h = 1e-3;
+	let numeric = (f(h) - f(-h)) / (2.0 * h);
+	assert!((numeric - analytic).length() < 1e-2 * numeric.length(), "{:?} instead of {:?}", analytic, numeric);
+}
+
+#[cfg(test)]
+fn test_differentials() -> RayDifferentials {
+	RayDifferentials {
+		dp_dx: Vec3::new(1.0, 0.0, 0.3),
+		dp_dy: Vec3::new(0.0, 1.2, -0.2),
+		dd_dx: Vec3::new(0.5, -0.2, 0.0),
+		dd_dy: Vec3::new(-0.1, 0.0, 0.6),
+	}
+}
+
+#[test]
+fn test_transfer_differentials() {
+	let ray = Ray { origin: Vec3::new(0.5, 2.0, -1.0), direction: Vec3::new(0.2, -1.0, 0.4).normalized() };
+	let normal = Vec3::new(0.3, 1.0, -0.2).normalized();
+	let distance = 2.5;
+	let plane_point = ray.point_at(distance);
+	// hit point of the offset ray on the tangent plane
+	let hit = |origin: Vec3, direction: Vec3| {
+		let t = Vec3::dot(plane_point - origin, normal) / Vec3::dot(direction, normal);
+		origin + direction * t
+	};
+
+	let rd = test_differentials();
+	let transferred = rd.transfer(ray, distance, normal);
+	assert_derivative(|h| hit(ray.origin + rd.dp_dx * h, ray.direction + rd.dd_dx * h), transferred.dp_dx);
+	assert_derivative(|h| hit(ray.origin + rd.dp_dy * h, ray.direction + rd.dd_dy * h), transferred.dp_dy);
+}
+
+#[test]
+fn test_reflect_differentials() {
+	let dir = Vec3::new(0.3, -1.0, 0.2).normalized();
+	let normal = Vec3::new(0.0, 1.0, 0.1).normalized();
+	let (dn_dx, dn_dy) = (Vec3::new(0.5, 0.0, 0.1), Vec3::new(0.0, -0.1, 0.4));
+	let reflect = |d: Vec3, n: Vec3| d - n * (2.0 * Vec3::dot(d, n));
+
+	let rd = test_differentials();
+	let reflected = rd.reflect(dir, normal, dn_dx, dn_dy);
+	assert_derivative(|h| reflect(dir + rd.dd_dx * h, normal + dn_dx * h), reflected.dd_dx);
+	assert_derivative(|h| reflect(dir + rd.dd_dy * h, normal + dn_dy * h), reflected.dd_dy);
+}
+
+#[test]
+fn test_refract_differentials() {
+	let dir = Vec3::new(0.3, -1.0, 0.2).normalized();
+	let normal = Vec3::new(0.0, 1.0, 0.1).normalized();
+	let (dn_dx, dn_dy) = (Vec3::new(0.5, 0.0, 0.1), Vec3::new(0.0, -0.1, 0.4));
+	let eta = 1.0 / 1.5;
+	// Snell's law, with the transmitted direction on the opposite side of `normal` to `d`
+	let refract = |d: Vec3, n: Vec3| {
+		let dn = Vec3::dot(d, n);
+		let cos_t = -(1.0 - eta * eta * (1.0 - dn * dn)).sqrt();
+		d * eta - n * (eta * dn - cos_t)
+	};
+
+	let rd = test_differentials();
+	let refracted = rd.refract(dir, refract(dir, normal), eta, normal, dn_dx, dn_dy);
+	assert_derivative(|h| refract(dir + rd.dd_dx * h, normal + dn_dx * h), refracted.dd_dx);
+	assert_derivative(|h| refract(dir + rd.dd_dy * h, normal + dn_dy * h), refracted.dd_dy);
+}
@@ -12,7 +12,7 @@ pub struct Triangle {
 	pub idxs: [Index; 3],
 }
 
-// N.B. There is a 1-1 correspondence between vertices and normals.
+// N.B. There is a 1-1 correspondence between vertices, normals, uvs and tangents.
 // Those are addressed through the indices stored in triangles.
 #[derive(Serialize, Deserialize)]
 pub struct Mesh {
@@ -23,6 +23,7 @@ pub struct Mesh {
 	triangles_e1: Vec<Vec3>,
 	triangles_e2: Vec<Vec3>,
 	bvh: BVH,
+	tangents: Vec<Vec3>,
 }
 
 impl Mesh {
@@ -59,6 +60,7 @@ impl Mesh {
 		};
 
 		let (triangles_e1, triangles_e2) = Mesh::compute_edges(&vertices[..], &triangles[..]);
+		let tangents = Mesh::compute_tangents(&vertices[..], &normals[..], &uvs[..], &triangles[..]);
 
 		Mesh {
 			vertices: vertices,
@@ -68,9 +70,66 @@ impl Mesh {
 			triangles_e1: triangles_e1,
 			triangles_e2: triangles_e2,
 			bvh: bvh,
+			tangents: tangents,
 		}
 	}
 
+	/// Derivatives with respect to u and v of a per-vertex attribute linearly interpolated on a
+	/// triangle, or `None` for degenerate uvs
+	fn triangle_derivatives(values: &[Vec3], uvs: &[(f32, f32)], t: &Triangle) -> Option<(Vec3, Vec3)> {
+		let v0 = values[t.idxs[0] as usize];
+		let edge1 = values[t.idxs[1] as usize] - v0;
+		let edge2 = values[t.idxs[2] as usize] - v0;
+		let uv0 = uvs[t.idxs[0] as usize];
+		let uv1 = uvs[t.idxs[1] as usize];
+		let uv2 = uvs[t.idxs[2] as usize];
+		let (du1, dv1) = (uv1.0 - uv0.0, uv1.1 - uv0.1);
+		let (du2, dv2) = (uv2.0 - uv0.0, uv2.1 - uv0.1);
+		let det = du1 * dv2 - du2 * dv1;
+		if det.abs() > 1e-12 {
+			Some(((edge1 * dv2 - edge2 * dv1) / det, (edge2 * du1 - edge1 * du2) / det))
+		} else {
+			None
+		}
+	}
+
+	/// Derivative of the position with respect to u on a triangle, falling back on an edge for degenerate uvs
+	fn triangle_dpdu(vertices: &[Vec3], uvs: &[(f32, f32)], t: &Triangle) -> Vec3 {
+		match Mesh::triangle_derivatives(vertices, uvs, t) {
+			Some((dpdu, _)) => dpdu,
+			None => vertices[t.idxs[1] as usize] - vertices[t.idxs[0] as usize],
+		}
+	}
+
+	/// Smooth per-vertex tangents, orthogonal to the vertex normals
+	///
+	/// The directions of increasing u of the adjacent triangles are averaged, weighted
+	/// by their area, so that normal maps do not show seams along triangle edges.
+	fn compute_tangents(vertices: &[Vec3], normals: &[Vec3], uvs: &[(f32, f32)], triangles: &[Triangle]) -> Vec<Vec3> {
+		let mut tangents = vec![Vec3::zero(); vertices.len()];
+		for t in triangles {
+			let v0 = vertices[t.idxs[0] as usize];
+			let area = Vec3::cross(vertices[t.idxs[1] as usize] - v0, vertices[t.idxs[2] as usize] - v0).length();
+			let dpdu = Mesh::triangle_dpdu(vertices, uvs, t);
+			let length = dpdu.length();
+			if length > 0.0 {
+				for &i in &t.idxs {
+					tangents[i as usize] += dpdu * (area / length);
+				}
+			}
+		}
+		for (tangent, &normal) in tangents.iter_mut().zip(normals) {
+			*tangent = *tangent - normal * Vec3::dot(normal, *tangent);
+			let length = tangent.length();
+			*tangent = if length > 1e-12 {
+				*tangent / length
+			} else {
+				Vec3::zero()
+			};
+		}
+		tangents
+	}
+
 	fn compute_edges(vertices: &[Vec3], triangles: &[Triangle]) -> (Vec<Vec3>, Vec<Vec3>) {
 		// cache triangle edges
 		let mut triangles_e1 = Vec::with_capacity(triangles.len());
@@ -127,10 +186,37 @@ impl Surface for Mesh {
 			let tu = uv0.0 * (1.0 - u - v) + uv1.0 * u + uv2.0 * v;
 			let tv = uv0.1 * (1.0 - u - v) + uv1.1 * u + uv2.1 * v;
 
+			let t0 = self.tangents[idxs[0] as usize];
+			let t1 = self.tangents[idxs[1] as usize];
+			let t2 = self.tangents[idxs[2] as usize];
+			let tangent = t0 * (1.0 - u - v) + t1 * u + t2 * v;
+			let tangent = if tangent.length() > 1e-6 {
+				tangent
+			} else {
+				Mesh::triangle_dpdu(&self.vertices, &self.uvs, &self.triangles[i])
+			};
+
+			let mut ng = Vec3::cross(self.triangles_e1[i], self.triangles_e2[i]).normalized();
+			if Vec3::dot(ng, n) < 0.0 {
+				ng = -ng;
+			}
+
+			let triangle = &self.triangles[i];
+			let (dpdu, dpdv) = Mesh::triangle_derivatives(&self.vertices, &self.uvs, triangle)
+				.unwrap_or((self.triangles_e1[i], self.triangles_e2[i]));
+			let (dndu, dndv) = Mesh::triangle_derivatives(&self.normals, &self.uvs, triangle)
+				.unwrap_or((Vec3::zero(), Vec3::zero()));
+
 			Some(Intersection {
 				distance: t,
 				normal: n,
+				geometric_normal: ng,
+				tangent,
 				uv: (tu, tv),
+				dpdu,
+				dpdv,
+				dndu,
+				dndv,
 			})
 		} else {
 			None
@@ -0,0 +1,111 @@
+use math::*;
+
+/// Hash of the integer coordinates of a lattice point
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+	let mut h = (x as u32).wrapping_mul(0x8da6b343)
+		^ (y as u32).wrapping_mul(0xd8163841)
+		^ (z as u32).wrapping_mul(0xcb1ab31f);
+	// finalizer of MurmurHash3
+	h ^= h >> 16;
+	h = h.wrapping_mul(0x85ebca6b);
+	h ^= h >> 13;
+	h = h.wrapping_mul(0xc2b2ae35);
+	h ^ (h >> 16)
+}
+
+/// Dot product of the offset `(x, y, z)` with one of the 12 gradients of improved Perlin noise
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+	match h % 12 {
+		0 => x + y,
+		1 => -x + y,
+		2 => x - y,
+		3 => -x - y,
+		4 => x + z,
+		5 => -x + z,
+		6 => x - z,
+		7 => -x - z,
+		8 => y + z,
+		9 => -y + z,
+		10 => y - z,
+		_ => -y - z,
+	}
+}
+
+fn fade(t: f32) -> f32 {
+	t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+	a + (b - a) * t
+}
+
+/// Improved Perlin noise (Perlin 2002), roughly in [-1, 1] and zero at lattice points
+pub fn perlin(p: Vec3) -> f32 {
+	let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+	let (x, y, z) = (fx as i32, fy as i32, fz as i32);
+	let (dx, dy, dz) = (p.x - fx, p.y - fy, p.z - fz);
+	let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+
+	let g = |i: i32, j: i32, k: i32| gradient(hash(x + i, y + j, z + k), dx - i as f32, dy - j as f32, dz - k as f32);
+	mix(
+		mix(mix(g(0, 0, 0), g(1, 0, 0), u), mix(g(0, 1, 0), g(1, 1, 0), u), v),
+		mix(mix(g(0, 0, 1), g(1, 0, 1), u), mix(g(0, 1, 1), g(1, 1, 1), u), v),
+		w,
+	)
+}
+
+/// Sum of `octaves` layers of `f` with doubling frequencies and halving amplitudes,
+/// normalized by the sum of the amplitudes
+fn octaves_sum<F: Fn(Vec3) -> f32>(p: Vec3, octaves: u32, f: F) -> f32 {
+	let mut sum = 0.0;
+	let mut amplitude = 1.0;
+	let mut total = 0.0;
+	let mut p = p;
+	for _ in 0..octaves.max(1) {
+		sum += amplitude * f(p);
+		total += amplitude;
+		amplitude *= 0.5;
+		p = p * 2.0;
+	}
+	sum / total
+}
+
+/// Fractional Brownian motion: octaves of Perlin noise, in [-1, 1]
+pub fn fbm(p: Vec3, octaves: u32) -> f32 {
+	octaves_sum(p, octaves, perlin)
+}
+
+/// Octaves of the absolute value of Perlin noise, in [0, 1]
+pub fn turbulence(p: Vec3, octaves: u32) -> f32 {
+	octaves_sum(p, octaves, |p| perlin(p).abs())
+}
+
+/// Cellular noise (Worley 1996): distance to the closest of random points scattered one per
+/// unit cell, in [0, 1]
+pub fn voronoi(p: Vec3) -> f32 {
+	let (x, y, z) = (p.x.floor() as i32, p.y.floor() as i32, p.z.floor() as i32);
+	let mut min_dist_sq = INFINITY;
+	for k in z - 1..z + 2 {
+		for j in y - 1..y + 2 {
+			for i in x - 1..x + 2 {
+				let h = hash(i, j, k);
+				let unit = |bits: u32| (bits & 0x3ff) as f32 / 1024.0;
+				let feature = Vec3::new(i as f32 + unit(h), j as f32 + unit(h >> 10), k as f32 + unit(h >> 20));
+				let d = feature - p;
+				min_dist_sq = min_dist_sq.min(Vec3::dot(d, d));
+			}
+		}
+	}
+	min_dist_sq.sqrt().min(1.0)
+}
+
+/// Veins of marble: a sine wave along X perturbed by turbulence, in [0, 1]
+pub fn marble(p: Vec3, octaves: u32) -> f32 {
+	0.5 + 0.5 * ((p.x + 4.0 * turbulence(p, octaves)) * PI).sin()
+}
+
+/// Growth rings of wood around the Y axis, slightly distorted by fBm, in [0, 1]
+pub fn wood(p: Vec3, octaves: u32) -> f32 {
+	let r = (p.x * p.x + p.z * p.z).sqrt() + 0.3 * fbm(p, octaves);
+	r - r.floor()
+}
@@ -35,11 +35,19 @@ impl Surface for Sphere {
 				let theta = normal.y.acos();
 				let u = phi * INV_2_PI;
 				let v = theta * INV_PI;
+				let dndu = Vec3::new(-normal.z, 0.0, normal.x) * (2.0 * PI);
+				let dndv = Vec3::new(normal.y * phi.cos(), -theta.sin(), normal.y * phi.sin()) * PI;
 				return Some(Intersection {
 					distance: t,
 					normal: normal,
+					geometric_normal: normal,
+					tangent: Vec3::new(-normal.z, 0.0, normal.x),
 					// TODO: floor not necessary?
 					uv: (u - u.floor(), v - v.floor()),
+					dpdu: dndu * self.radius,
+					dpdv: dndv * self.radius,
+					dndu,
+					dndv,
 				});
 			}
 		}
@@ -124,7 +132,13 @@ impl Surface for Parallelogram {
 		Some(Intersection {
 			distance: t,
 			normal: self.normal,
+			geometric_normal: self.normal,
+			tangent: self.edge1,
 			uv: (u - u.floor(), v - v.floor()),
+			// exact when the edges are orthogonal
+			dpdu: self.edge1,
+			dpdv: self.edge2,
+			..Default::default()
 		})
 	}
 
@@ -213,7 +227,12 @@ impl Surface for Disk {
 		Some(Intersection {
 			distance: t,
 			normal: self.normal,
+			geometric_normal: self.normal,
+			tangent: self.u_axis,
 			uv: (u - u.floor(), v - v.floor()),
+			dpdu: self.u_axis,
+			dpdv: self.v_axis,
+			..Default::default()
 		})
 	}
 
@@ -3,20 +3,25 @@ use material::*;
 use light::*;
 use math::*;
 use bvh::BVH;
+use texture::TexCoords;
 use std::sync::Arc;
 
 pub enum Object {
 	Emitter(AreaLight),
+	/// Light without any surface; it is never hit by rays
+	PointEmitter(PointLight),
 	Scatterer {
 		surface: Box<Surface + Send + Sync>,
 		material: Arc<Material>
 	},
+	/// Scatterer with an emissive material, also sampled as a light
+	EmissiveScatterer(EmissiveSurface),
 }
 
 impl Object {
 	fn is_emitter(&self) -> bool {
 		match self {
-			Object::Emitter(_) => true,
+			Object::Emitter(_) | Object::PointEmitter(_) | Object::EmissiveScatterer(_) => true,
 			_ => false,
 		}
 	}
@@ -26,32 +31,42 @@ impl Surface for Object {
 	fn intersect(&self, ray: Ray) -> Option<Intersection> {
 		match *self {
 			Object::Emitter(ref area_light) => area_light.surface.intersect(ray),
+			Object::PointEmitter(_) => None,
 			Object::Scatterer { ref surface, .. } => surface.intersect(ray),
+			Object::EmissiveScatterer(ref light) => light.surface.intersect(ray),
 		}
 	}
 
 	fn aabb(&self) -> AABB {
 		match *self {
 			Object::Emitter(ref area_light) => area_light.surface.aabb(),
+			Object::PointEmitter(ref point_light) => AABB::from_point(point_light.position),
 			Object::Scatterer { ref surface, .. } => surface.aabb(),
+			Object::EmissiveScatterer(ref light) => light.surface.aabb(),
 		}
 	}
 }
 
+/// Maximum number of transparent surfaces a ray can go through
+const MAX_PASS_THROUGH: usize = 64;
+
 pub(crate) enum Hit<'a> {
 	Emitter(&'a Light, f32),
-	Scatterer(Intersection, &'a Material),
+	/// Scattering surface, with the light sampling it if it is emissive
+	Scatterer(Intersection, &'a Material, Option<&'a Light>),
 }
 
 pub struct Scene {
 	objects: Vec<Object>,
-	background: Option<EnvMap>,
+	background: Option<Box<Light + Send + Sync>>,
+	background_visible: bool,
+	spectral: bool,
 	light_idxs: Vec<usize>,
 	bvh: BVH,
 }
 
 impl Scene {
-	pub fn new(background: Option<EnvMap>, objects: Vec<Object>) -> Scene {
+	pub fn new(background: Option<Box<Light + Send + Sync>>, objects: Vec<Object>) -> Scene {
 		let mut objects = objects;
 
 		let proj_centroid = |o: &Object, axis| o.aabb().center()[axis];
@@ -64,7 +79,26 @@ impl Scene {
 			.map(|(i, _)| i)
 			.collect();
 
-		Scene { objects, background, bvh, light_idxs }
+		Scene { objects, background, background_visible: true, spectral: false, bvh, light_idxs }
+	}
+
+	/// Show or hide the background from camera rays; a hidden background still lights the scene
+	pub fn set_background_visible(&mut self, visible: bool) {
+		self.background_visible = visible;
+	}
+
+	pub(crate) fn is_background_visible(&self) -> bool {
+		self.background_visible
+	}
+
+	/// Render with wavelengths sampled along each path rather than with RGB colors,
+	/// e.g. for the dispersion of light through glass
+	pub fn set_spectral(&mut self, spectral: bool) {
+		self.spectral = spectral;
+	}
+
+	pub(crate) fn is_spectral(&self) -> bool {
+		self.spectral
 	}
 
 	pub(crate) fn intersect(&self, ray: Ray) -> Option<Hit> {
@@ -73,23 +107,35 @@ impl Scene {
 			let o: &Object = &self.objects[i];
 			match o.intersect(ray) {
 				None => (-1.0, Default::default()),
-				Some(its) => (its.distance, (its.normal, its.uv)),
+				Some(its) => (its.distance, its),
 			}
 		};
-		let (t, i, (n, uv)) = self.bvh.intersect(&intersect_item, ray);
 
-		if t > 0.0 {
-			Some(match self.objects[i] {
-				Object::Scatterer { ref material, .. } => {
-					Hit::Scatterer(Intersection { distance: t, normal: n, uv }, material.as_ref())
-				}
-				Object::Emitter(ref area_light) => {
-					Hit::Emitter(area_light, t)
-				}
-			})
-		} else {
-			self.background.as_ref().map(|envmap| Hit::Emitter(envmap, INFINITY))
+		// fully transparent surfaces are skipped, so keep track of the distance travelled through them
+		let mut ray = ray;
+		let mut offset = 0.0;
+		for _ in 0..MAX_PASS_THROUGH {
+			let (t, i, its) = self.bvh.intersect(&intersect_item, ray);
+
+			if t <= 0.0 {
+				return self.background.as_ref().map(|background| Hit::Emitter(background.as_ref(), INFINITY));
+			}
+
+			let (material, light) = match self.objects[i] {
+				Object::Scatterer { ref material, .. } => (material, None),
+				Object::EmissiveScatterer(ref light) => (&light.material, Some(light as &Light)),
+				Object::Emitter(ref area_light) => return Some(Hit::Emitter(area_light, offset + t)),
+				Object::PointEmitter(_) => unreachable!(),
+			};
+			if material.opacity(TexCoords::new(its.uv, ray.point_at(t))) <= 0.0 {
+				ray.origin = ray.point_at(t + 2.0 * EPSILON);
+				offset += t + 2.0 * EPSILON;
+				continue;
+			}
+			return Some(Hit::Scatterer(Intersection { distance: offset + t, ..its }, material.as_ref(), light));
 		}
+
+		None
 	}
 
 	pub(crate) fn nb_lights(&self) -> usize {
@@ -99,8 +145,10 @@ impl Scene {
 	pub(crate) fn get_light(&self, i: usize) -> Option<&Light> {
 		match self.light_idxs.get(i).and_then(|&i_obj| self.objects.get(i_obj)) {
 			Some(Object::Emitter(light)) => Some(light),
+			Some(Object::PointEmitter(light)) => Some(light),
+			Some(Object::EmissiveScatterer(light)) => Some(light),
 			_ => match self.background {
-				Some(ref envmap) => Some(envmap),
+				Some(ref background) => Some(background.as_ref()),
 				None => None,
 			},
 		}
@@ -127,22 +175,46 @@ impl Scene {
 	}
 	*/
 
-	pub(crate) fn occluded(&self, point: Vec3, normal: Vec3, dir: Vec3, max_dist: f32) -> bool {
+	/// Fraction of light going through the scene from `point` along `dir`, over `max_dist`
+	///
+	/// Light is only transmitted through (partially) transparent surfaces.
+	pub(crate) fn transmittance(&self, point: Vec3, normal: Vec3, dir: Vec3, max_dist: f32) -> f32 {
 		if Vec3::dot(dir, normal) <= 0.0 {
-			return true;
+			return 0.0;
 		}
 
 		let intersect_item = |ray, i| {
 			let o: &Object = &self.objects[i];
 			match o.intersect(ray) {
-				None => (-1.0, ()),
-				Some(its) => (its.distance, ()),
+				None => (-1.0, Default::default()),
+				Some(its) => (its.distance, its.uv),
 			}
 		};
 
-		let shadow_ray = Ray { origin: point + normal * EPSILON, direction: dir };
-		let (t, _, ()) = self.bvh.intersect(&intersect_item, shadow_ray);
+		let mut shadow_ray = Ray { origin: point + normal * EPSILON, direction: dir };
+		let mut max_dist = max_dist - 2.0 * EPSILON;
+		let mut transmittance = 1.0;
+		for _ in 0..MAX_PASS_THROUGH {
+			let (t, i, uv) = self.bvh.intersect(&intersect_item, shadow_ray);
+			if !(t > 0.0 && t < max_dist) {
+				return transmittance;
+			}
+			let tc = TexCoords::new(uv, shadow_ray.point_at(t));
+
+			let opacity = match self.objects[i] {
+				Object::Scatterer { ref material, .. } => material.opacity(tc),
+				Object::EmissiveScatterer(ref light) => light.material.opacity(tc),
+				_ => 1.0,
+			};
+			transmittance *= 1.0 - opacity;
+			if transmittance <= 0.0 {
+				return 0.0;
+			}
+
+			shadow_ray.origin = shadow_ray.point_at(t + 2.0 * EPSILON);
+			max_dist -= t + 2.0 * EPSILON;
+		}
 
-		t > 0.0 && t < max_dist - 2.0 * EPSILON
+		0.0
 	}
 }
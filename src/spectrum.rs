@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use math::*;
+
+/// Range of visible wavelengths (in nanometers) over which spectra are integrated
+pub const LAMBDA_MIN: f32 = 380.0;
+pub const LAMBDA_MAX: f32 = 780.0;
+
+/// Wavelength at which dispersive materials are evaluated when rendering in RGB (sodium D line)
+pub const REFERENCE_WAVELENGTH: f32 = 589.3;
+
+/// Spectral quantity tabulated at increasing wavelengths (in nanometers) and
+/// linearly interpolated in between
+pub struct Spectrum {
+	wavelengths: Vec<f32>,
+	values: Vec<f32>,
+}
+
+impl Spectrum {
+	pub fn new(wavelengths: Vec<f32>, values: Vec<f32>) -> Spectrum {
+		assert!(!wavelengths.is_empty() && wavelengths.len() == values.len(), "invalid spectrum");
+		assert!(wavelengths.windows(2).all(|w| w[0] < w[1]), "spectrum wavelengths must be increasing");
+		Spectrum { wavelengths, values }
+	}
+
+	/// Value at the wavelength `lambda`, clamped to the tabulated range
+	pub fn eval(&self, lambda: f32) -> f32 {
+		let ws = &self.wavelengths;
+		let n = ws.len();
+		if lambda <= ws[0] {
+			return self.values[0];
+		}
+		if lambda >= ws[n - 1] {
+			return self.values[n - 1];
+		}
+		let i = ws.iter().position(|&w| w > lambda).unwrap() - 1;
+		let t = (lambda - ws[i]) / (ws[i + 1] - ws[i]);
+		self.values[i] * (1.0 - t) + self.values[i + 1] * t
+	}
+
+	/// Linear sRGB color of the spectrum, as a reflectance
+	///
+	/// The spectrum is projected on the CIE color matching functions, and the
+	/// result is normalized so that a constant spectrum maps to a gray color.
+	pub fn to_rgb(&self) -> Vec3 {
+		xyz_to_rgb(integrate_xyz(|lambda| self.eval(lambda))) / white_rgb()
+	}
+}
+
+/// Sample the wavelengths carried by a path, one per channel of a `Vec3`
+///
+/// The first one, the hero wavelength, is uniformly distributed over the visible
+/// range, and the other two are evenly spaced from it (Wilkie et al. 2014).
+pub fn sample_wavelengths(u: f32) -> Vec3 {
+	let lambda = |offset: f32| LAMBDA_MIN + ((u + offset) % 1.0) * (LAMBDA_MAX - LAMBDA_MIN);
+	Vec3::new(lambda(0.0), lambda(1.0 / 3.0), lambda(2.0 / 3.0))
+}
+
+/// Upsample a linear sRGB color to a smooth spectrum, evaluated at each of the
+/// wavelengths `lambdas`
+///
+/// The spectrum is a combination of red, green and blue basis functions which sum to
+/// one, so that reflectances stay below one and white is constant. Going back to RGB
+/// is only approximate, with slightly desaturated colors.
+pub fn rgb_to_spectral(rgb: Vec3, lambdas: Vec3) -> Vec3 {
+	let ramp = |lambda: f32, start: f32, end: f32| ((lambda - start) / (end - start)).max(0.0).min(1.0);
+	lambdas.map(|lambda| {
+		let b = 1.0 - ramp(lambda, 480.0, 510.0);
+		let r = ramp(lambda, 570.0, 600.0);
+		r * rgb.x + (1.0 - r - b) * rgb.y + b * rgb.z
+	})
+}
+
+/// Linear sRGB estimate of the radiance of a path, given its values at the wavelengths
+/// drawn by `sample_wavelengths`
+pub fn spectral_to_rgb(values: Vec3, lambdas: Vec3) -> Vec3 {
+	let xyz = cie_xyz(lambdas.x) * values.x + cie_xyz(lambdas.y) * values.y + cie_xyz(lambdas.z) * values.z;
+	// divide by the density of each wavelength, averaged over the three of them
+	xyz_to_rgb(xyz * ((LAMBDA_MAX - LAMBDA_MIN) / 3.0)) / white_rgb()
+}
+
+/// RGB color of a constant unit spectrum, before normalization
+fn white_rgb() -> Vec3 {
+	static WHITE: OnceLock<Vec3> = OnceLock::new();
+	*WHITE.get_or_init(|| xyz_to_rgb(integrate_xyz(|_| 1.0)))
+}
+
+/// Integrate a spectral function against the CIE 1931 color matching functions
+fn integrate_xyz<F: Fn(f32) -> f32>(f: F) -> Vec3 {
+	const STEP: f32 = 1.0;
+	let n = ((LAMBDA_MAX - LAMBDA_MIN) / STEP) as usize;
+	let mut sum = Vec3::zero();
+	for i in 0..n {
+		let lambda = LAMBDA_MIN + (i as f32 + 0.5) * STEP;
+		sum += cie_xyz(lambda) * f(lambda);
+	}
+	sum * STEP
+}
+
+/// CIE 1931 2° color matching functions, with the multi-lobe fit of Wyman et al. 2013
+pub fn cie_xyz(lambda: f32) -> Vec3 {
+	let g = |mu: f32, sigma1: f32, sigma2: f32| {
+		let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+		(-0.5 * t * t).exp()
+	};
+	Vec3::new(
+		1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+		0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+		1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+	)
+}
+
+/// Convert CIE XYZ to linear sRGB (D65 white point)
+pub fn xyz_to_rgb(xyz: Vec3) -> Vec3 {
+	Vec3::new(
+		3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+		-0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+		0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+	)
+}
+
+/// Load the real and imaginary parts of a complex refractive index, as exported
+/// by refractiveindex.info
+///
+/// Both the CSV exports (`wl,n` and `wl,k` sections) and the YAML database files
+/// (`tabulated nk`, `tabulated n` and `tabulated k` data) are supported, with
+/// wavelengths in micrometers.
+pub fn load_nk<P: AsRef<Path>>(path: P) -> (Spectrum, Spectrum) {
+	let mut content = String::new();
+	File::open(&path).expect("failed to open refractive index data")
+		.read_to_string(&mut content).expect("failed to read refractive index data");
+
+	let is_yaml = path.as_ref().extension().map_or(false, |e| e == "yml" || e == "yaml");
+	let (n, k) = if is_yaml { parse_yaml(&content) } else { parse_csv(&content) };
+
+	let to_spectrum = |mut data: Vec<(f32, f32)>| {
+		data.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+		data.dedup_by(|a, b| a.0 == b.0);
+		let (wavelengths, values) = data.into_iter().map(|(w, v)| (w * 1000.0, v)).unzip();
+		Spectrum::new(wavelengths, values)
+	};
+	assert!(!n.is_empty(), "refractive index data has no real part");
+	let k = if k.is_empty() { vec![(LAMBDA_MIN / 1000.0, 0.0)] } else { k };
+	(to_spectrum(n), to_spectrum(k))
+}
+
+/// Parse the numbers of a data line, if it only contains numbers
+fn parse_numbers(line: &str) -> Option<Vec<f32>> {
+	let values: Result<Vec<f32>, _> = line
+		.split(|c: char| c.is_whitespace() || c == ',')
+		.filter(|s| !s.is_empty())
+		.map(|s| s.parse::<f32>())
+		.collect();
+	values.ok().filter(|v| !v.is_empty())
+}
+
+fn parse_csv(content: &str) -> (Vec<(f32, f32)>, Vec<(f32, f32)>) {
+	let mut n = Vec::new();
+	let mut k = Vec::new();
+	let mut in_k = false;
+	for line in content.lines() {
+		let line = line.trim();
+		if line.starts_with("wl") {
+			in_k = line.split(',').nth(1).map_or(false, |c| c.trim() == "k");
+			continue;
+		}
+		match parse_numbers(line).as_ref().map(|v| &v[..]) {
+			Some(&[w, v]) => if in_k { k.push((w, v)) } else { n.push((w, v)) },
+			Some(&[w, vn, vk]) => {
+				n.push((w, vn));
+				k.push((w, vk));
+			}
+			Some(_) => panic!("invalid refractive index data: '{}'", line),
+			None => {}
+		}
+	}
+	(n, k)
+}
+
+fn parse_yaml(content: &str) -> (Vec<(f32, f32)>, Vec<(f32, f32)>) {
+	let mut n = Vec::new();
+	let mut k = Vec::new();
+	// kind of the data block being read: "nk", "n" or "k"
+	let mut kind = "";
+	for line in content.lines() {
+		let line = line.trim().trim_start_matches("- ").trim();
+		if line.starts_with("type:") {
+			kind = match line["type:".len()..].trim() {
+				"tabulated nk" => "nk",
+				"tabulated n" => "n",
+				"tabulated k" => "k",
+				t => {
+					println!("Warning: refractive index data of type '{}' is ignored", t);
+					""
+				}
+			};
+			continue;
+		}
+		let values = match parse_numbers(line) {
+			Some(v) => v,
+			None => continue,
+		};
+		match (kind, &values[..]) {
+			("nk", &[w, vn, vk]) => {
+				n.push((w, vn));
+				k.push((w, vk));
+			}
+			("n", &[w, v]) => n.push((w, v)),
+			("k", &[w, v]) => k.push((w, v)),
+			("", _) => {}
+			_ => panic!("invalid refractive index data: '{}'", line),
+		}
+	}
+	(n, k)
+}
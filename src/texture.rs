@@ -1,19 +1,115 @@
 use std::fs::File;
 use std::io::{BufReader, Write, BufWriter};
 use std::path::Path;
+use std::sync::OnceLock;
 use image;
-use math::{Vec3, bilerp};
+use math::{Vec3, bilerp, lerp};
 use camera::Tonemap;
+use noise;
+
+/// Point of a surface where a texture is evaluated
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TexCoords {
+	pub uv: (f32, f32),
+	/// Position in world space, for solid textures
+	pub position: Vec3,
+	/// Derivatives of `uv` along the image axes, giving the footprint to filter over;
+	/// zero when unknown
+	pub duv_dx: (f32, f32),
+	pub duv_dy: (f32, f32),
+}
+
+impl TexCoords {
+	pub fn new(uv: (f32, f32), position: Vec3) -> TexCoords {
+		TexCoords { uv, position, ..Default::default() }
+	}
+}
 
 pub enum Texture {
 	Constant(Vec3),
 	Grid(Vec3, Vec3, usize, f32),
 	Checker { on_color: Vec3, off_color: Vec3, resolution: (f32, f32) },
 	Bitmap(Image),
+	/// Procedural noise mapped to colors by a ramp
+	Noise { noise: Noise, frequency: f32, octaves: u32, solid: bool, ramp: ColorRamp },
+}
+
+/// Kinds of procedural noise, all with values in [0, 1]
+#[derive(Copy, Clone, Debug)]
+pub enum Noise {
+	Perlin,
+	/// Fractional Brownian motion, i.e. octaves of Perlin noise
+	Fbm,
+	Turbulence,
+	/// Distance to the closest of randomly scattered points
+	Voronoi,
+	Marble,
+	/// Rings around the V axis in texture space, or the Y axis for solid noise
+	Wood,
+}
+
+impl Noise {
+	fn eval(self, p: Vec3, octaves: u32) -> f32 {
+		match self {
+			Noise::Perlin => 0.5 + 0.5 * noise::perlin(p),
+			Noise::Fbm => 0.5 + 0.5 * noise::fbm(p, octaves),
+			Noise::Turbulence => noise::turbulence(p, octaves),
+			Noise::Voronoi => noise::voronoi(p),
+			Noise::Marble => noise::marble(p, octaves),
+			Noise::Wood => noise::wood(p, octaves),
+		}
+	}
+}
+
+/// Piecewise linear map from [0, 1] to colors, given by colors at increasing positions
+pub struct ColorRamp {
+	stops: Vec<(f32, Vec3)>,
+}
+
+impl ColorRamp {
+	pub fn new(stops: Vec<(f32, Vec3)>) -> ColorRamp {
+		assert!(!stops.is_empty(), "empty color ramp");
+		let mut stops = stops;
+		stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+		ColorRamp { stops }
+	}
+
+	/// Ramp from black to white
+	pub fn gray() -> ColorRamp {
+		ColorRamp::new(vec![(0.0, Vec3::zero()), (1.0, Vec3::thrice(1.0))])
+	}
+
+	pub fn eval(&self, t: f32) -> Vec3 {
+		let stops = &self.stops;
+		match stops.iter().position(|s| s.0 > t) {
+			Some(0) => stops[0].1,
+			Some(i) => {
+				let (t0, c0) = stops[i - 1];
+				let (t1, c1) = stops[i];
+				lerp(c0, c1, (t - t0) / (t1 - t0))
+			}
+			None => stops[stops.len() - 1].1,
+		}
+	}
+}
+
+/// Integral from 0 to `x` of the indicator function of odd integer parts
+fn odd_integral(x: f32) -> f32 {
+	let h = x / 2.0;
+	h.floor() + 2.0 * (h - h.floor() - 0.5).max(0.0)
 }
 
 impl Texture {
-	pub fn eval(&self, (u, v): (f32, f32)) -> Vec3 {
+	/// Whether the texture is zero everywhere
+	pub fn is_black(&self) -> bool {
+		match *self {
+			Texture::Constant(c) => c.max_elem() <= 0.0,
+			_ => false,
+		}
+	}
+
+	pub fn eval(&self, tc: TexCoords) -> Vec3 {
+		let (u, v) = tc.uv;
 		match *self {
 			Texture::Constant(c) => c,
 			Texture::Grid(c1, c2, s, w) => {
@@ -22,22 +118,47 @@ impl Texture {
 				if in_band { c2 } else { c1 }
 			},
 			Texture::Checker { on_color, off_color, resolution } => {
-				let ui = (resolution.0 * u) as i32;
-				let vi = (resolution.1 * v) as i32;
-				let on = (ui ^ vi) & 1 != 0;
-				if on { on_color } else { off_color }
+				// half widths of the footprint, in checker cells; wider footprints are averaged over
+				// the whole texture anyway (this also discards infinite or NaN footprints)
+				let ds = (tc.duv_dx.0.abs().max(tc.duv_dy.0.abs()) * resolution.0).min(resolution.0);
+				let dt = (tc.duv_dx.1.abs().max(tc.duv_dy.1.abs()) * resolution.1).min(resolution.1);
+				let (s, t) = (resolution.0 * u, resolution.1 * v);
+				if (s - ds).floor() == (s + ds).floor() && (t - dt).floor() == (t + dt).floor() {
+					let ui = s as i32;
+					let vi = t as i32;
+					let on = (ui ^ vi) & 1 != 0;
+					if on { on_color } else { off_color }
+				} else {
+					// box filter the checker over the footprint, in closed form
+					let odd_s = (odd_integral(s + ds) - odd_integral(s - ds)) / (2.0 * ds).max(1e-8);
+					let odd_t = (odd_integral(t + dt) - odd_integral(t - dt)) / (2.0 * dt).max(1e-8);
+					let on = odd_s + odd_t - 2.0 * odd_s * odd_t;
+					lerp(off_color, on_color, on)
+				}
 			},
 			Texture::Bitmap(ref img) => {
-				img.eval((u, v))
+				img.eval_filtered((u, v), tc.duv_dx, tc.duv_dy)
+			},
+			Texture::Noise { noise, frequency, octaves, solid, ref ramp } => {
+				// texture space is mapped on the XZ plane
+				let p = if solid { tc.position } else { Vec3::new(u, 0.0, v) };
+				ramp.eval(noise.eval(p * frequency, octaves))
 			},
 		}
 	}
 }
 
+/// Ratio of the axes of elliptic footprints above which EWA filtering is used
+const MIN_EWA_ANISOTROPY: f32 = 2.0;
+/// Elliptic footprints are widened to this ratio of their axes at most, to bound the cost of EWA
+const MAX_ANISOTROPY: f32 = 8.0;
+
 pub struct Image {
 	pub width: usize,
 	pub height: usize,
 	pixels: Vec<Vec3>,
+	/// Coarser levels of the MIP pyramid, built on first use
+	mipmaps: OnceLock<Vec<Image>>,
 }
 
 impl Image {
@@ -49,6 +170,21 @@ impl Image {
 			width: width as usize,
 			height: height as usize,
 			pixels: img.pixels().map(|p| gamma_decode(p.data)).collect(),
+			mipmaps: OnceLock::new(),
+		}
+	}
+
+	/// Load an LDR image holding data rather than colors, e.g. a normal map, without gamma decoding
+	pub fn load_linear<P: AsRef<Path>>(filepath: P) -> Image {
+		let img = image::open(&filepath).expect("failed to load texture").to_rgb();
+		let (width, height) = img.dimensions();
+		let f = |v| v as f32 / 255.0;
+
+		Image {
+			width: width as usize,
+			height: height as usize,
+			pixels: img.pixels().map(|p| Vec3::new(f(p.data[0]), f(p.data[1]), f(p.data[2]))).collect(),
+			mipmaps: OnceLock::new(),
 		}
 	}
 
@@ -62,7 +198,22 @@ impl Image {
 			width: meta.width as usize,
 			height: meta.height as usize,
 			pixels: pixels.iter().map(|p| Vec3::new(p[0], p[1], p[2])).collect(),
+			mipmaps: OnceLock::new(),
+		}
+	}
+
+	pub fn from_pixels(width: usize, height: usize, pixels: Vec<Vec3>) -> Image {
+		assert_eq!(pixels.len(), width * height);
+		Image { width, height, pixels, mipmaps: OnceLock::new() }
+	}
+
+	/// Extract the rectangular region of `width`×`height` pixels starting at (`x`, `y`)
+	pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Image {
+		let mut pixels = Vec::with_capacity(width * height);
+		for j in y..y + height {
+			pixels.extend_from_slice(&self.pixels[j * self.width + x..j * self.width + x + width]);
 		}
+		Image { width, height, pixels, mipmaps: OnceLock::new() }
 	}
 
 	pub fn get(&self, x: usize, y: usize) -> Vec3 {
@@ -102,6 +253,174 @@ impl Image {
 		let v11 = self.get(x1, y1);
 		bilerp(v00, v01, v10, v11, (dx, dy))
 	}
+
+	/// Evaluate the texture over the footprint given by the derivatives of the parametric
+	/// coordinates along the image axes, using the MIP pyramid
+	///
+	/// Elongated footprints are filtered with an elliptical weighted average (Heckbert 1989),
+	/// and the other ones with a cheaper trilinear interpolation.
+	pub fn eval_filtered(&self, uv: (f32, f32), duv_dx: (f32, f32), duv_dy: (f32, f32)) -> Vec3 {
+		// axes of the footprint in texels of the full resolution image
+		let to_texels = |(du, dv): (f32, f32)| (du * self.width as f32, dv * self.height as f32);
+		let length = |(x, y): (f32, f32)| (x * x + y * y).sqrt();
+		let (mut major, mut minor) = (to_texels(duv_dx), to_texels(duv_dy));
+		if length(major) < length(minor) {
+			::std::mem::swap(&mut major, &mut minor);
+		}
+		let major_length = length(major);
+		let minor_length = length(minor);
+
+		if !(major_length > 1.0) {
+			// magnification, or unknown footprint
+			return self.eval(uv);
+		}
+		if major_length < MIN_EWA_ANISOTROPY * minor_length {
+			return self.eval_trilinear(uv, major_length);
+		}
+
+		// widen too thin footprints
+		let minor_length = if minor_length * MAX_ANISOTROPY < major_length {
+			let widened = major_length / MAX_ANISOTROPY;
+			minor = (-major.1 / MAX_ANISOTROPY, major.0 / MAX_ANISOTROPY);
+			widened
+		} else {
+			minor_length
+		};
+
+		// the minor axis covers about one texel of the chosen level
+		let mipmaps = self.mipmaps();
+		let lod = minor_length.log2().max(0.0);
+		let level = lod as usize;
+		if level >= mipmaps.len() {
+			return self.level(mipmaps.len()).eval(uv);
+		}
+		let t = lod - level as f32;
+		lerp(
+			self.eval_ewa(self.level(level), uv, major, minor),
+			self.eval_ewa(self.level(level + 1), uv, major, minor),
+			t,
+		)
+	}
+
+	/// Interpolate between the bilinear lookups of the two MIP levels closest to a footprint
+	/// `width` texels wide
+	pub fn eval_trilinear(&self, uv: (f32, f32), width: f32) -> Vec3 {
+		let nb_levels = self.mipmaps().len() + 1;
+		let lod = width.max(1.0).log2();
+		let level = lod as usize;
+		if level + 1 >= nb_levels {
+			return self.level(nb_levels - 1).eval(uv);
+		}
+		let t = lod - level as f32;
+		lerp(self.level(level).eval(uv), self.level(level + 1).eval(uv), t)
+	}
+
+	/// Gaussian weighted average of the texels of `img`, a level of the MIP pyramid, inside
+	/// the ellipse of axes `major` and `minor`, given in texels of the full resolution image
+	fn eval_ewa(&self, img: &Image, (u, v): (f32, f32), major: (f32, f32), minor: (f32, f32)) -> Vec3 {
+		// convert to the texture coordinates of the level, as in `eval`
+		let sx = img.width as f32 / self.width as f32;
+		let sy = img.height as f32 / self.height as f32;
+		let tu = img.width as f32 * u - 0.5;
+		let tv = img.height as f32 * (1.0 - v) - 0.5;
+		let (a0, a1) = ((major.0 * sx, -major.1 * sy), (minor.0 * sx, -minor.1 * sy));
+
+		// implicit equation a x² + b xy + c y² < 1 of the ellipse, enlarged so that it covers
+		// at least a texel
+		let a = a0.1 * a0.1 + a1.1 * a1.1 + 1.0;
+		let b = -2.0 * (a0.0 * a0.1 + a1.0 * a1.1);
+		let c = a0.0 * a0.0 + a1.0 * a1.0 + 1.0;
+		let inv_f = 1.0 / (a * c - b * b * 0.25);
+		let (a, b, c) = (a * inv_f, b * inv_f, c * inv_f);
+
+		// bounding box of the ellipse
+		let det = 4.0 * a * c - b * b;
+		let half_u = 2.0 * (c / det).sqrt();
+		let half_v = 2.0 * (a / det).sqrt();
+		let (x0, x1) = ((tu - half_u).ceil() as isize, (tu + half_u).floor() as isize);
+		let (y0, y1) = ((tv - half_v).ceil() as isize, (tv + half_v).floor() as isize);
+
+		const ALPHA: f32 = 2.0;
+		let mut sum = Vec3::zero();
+		let mut sum_weights = 0.0;
+		for y in y0..y1 + 1 {
+			let dy = y as f32 - tv;
+			for x in x0..x1 + 1 {
+				let dx = x as f32 - tu;
+				let r2 = a * dx * dx + b * dx * dy + c * dy * dy;
+				if r2 < 1.0 {
+					let w = (-ALPHA * r2).exp() - (-ALPHA).exp();
+					sum += img.get(modulo(x, img.width as isize), modulo(y, img.height as isize)) * w;
+					sum_weights += w;
+				}
+			}
+		}
+		if sum_weights > 0.0 {
+			sum / sum_weights
+		} else {
+			img.eval((u, v))
+		}
+	}
+
+	/// Level of the MIP pyramid, from the full resolution image at 0
+	fn level(&self, level: usize) -> &Image {
+		if level == 0 { self } else { &self.mipmaps()[level - 1] }
+	}
+
+	/// Levels of the MIP pyramid coarser than the image, down to a single pixel
+	fn mipmaps(&self) -> &[Image] {
+		self.mipmaps.get_or_init(|| {
+			let mut levels: Vec<Image> = Vec::new();
+			loop {
+				let next = {
+					let last = levels.last().unwrap_or(self);
+					if last.width == 1 && last.height == 1 {
+						break;
+					}
+					last.downsample()
+				};
+				levels.push(next);
+			}
+			levels
+		})
+	}
+
+	/// Image of half the resolution, each pixel averaging a box of the original one
+	fn downsample(&self) -> Image {
+		let width = (self.width / 2).max(1);
+		let height = (self.height / 2).max(1);
+		let mut pixels = Vec::with_capacity(width * height);
+		for y in 0..height {
+			let (y0, y1) = (y * self.height / height, (y + 1) * self.height / height);
+			for x in 0..width {
+				let (x0, x1) = (x * self.width / width, (x + 1) * self.width / width);
+				let mut sum = Vec3::zero();
+				for j in y0..y1 {
+					for i in x0..x1 {
+						sum += self.get(i, j);
+					}
+				}
+				pixels.push(sum / ((x1 - x0) * (y1 - y0)) as f32);
+			}
+		}
+		Image::from_pixels(width, height, pixels)
+	}
+
+	/// Evaluate the texture using bilinear interpolation and image coordinates in [0;1]²
+	/// starting from the top-left corner; off-boundaries coordinates are clamped
+	pub fn eval_clamped(&self, (s, t): (f32, f32)) -> Vec3 {
+		let tx = (self.width  as f32 * s - 0.5).max(0.0).min((self.width  - 1) as f32);
+		let ty = (self.height as f32 * t - 0.5).max(0.0).min((self.height - 1) as f32);
+
+		let x0 = tx.floor() as usize;
+		let y0 = ty.floor() as usize;
+		let x1 = (x0 + 1).min(self.width - 1);
+		let y1 = (y0 + 1).min(self.height - 1);
+		let dx = tx - x0 as f32;
+		let dy = ty - y0 as f32;
+
+		bilerp(self.get(x0, y0), self.get(x1, y0), self.get(x0, y1), self.get(x1, y1), (dx, dy))
+	}
 }
 
 /// Non-negative remainder of a divided by b.
@@ -152,3 +471,67 @@ pub fn write_hdr(path: &str, width: usize, height: usize, buf: &[(Vec3, f32)]) {
 	enc.encode(&data[..], width, height).unwrap();
 }
 */
+
+/// Image of `width`×`height` pixels with arbitrary but reproducible values
+#[cfg(test)]
+fn test_image(width: usize, height: usize) -> Image {
+	let pixels = (0..width * height).map(|i| Vec3::new(
+		(i * 37 % 101) as f32 / 100.0,
+		(i * 53 % 89) as f32 / 88.0,
+		(i * 11 % 7) as f32 / 6.0,
+	)).collect();
+	Image::from_pixels(width, height, pixels)
+}
+
+#[test]
+fn test_filtered_whole_image_footprint() {
+	let img = test_image(32, 16);
+	let average = img.pixels.iter().fold(Vec3::zero(), |a, &p| a + p) / img.pixels.len() as f32;
+	for &uv in &[(0.3, 0.6), (0.9, 0.1)] {
+		let v = img.eval_filtered(uv, (1.0, 0.0), (0.0, 1.0));
+		assert!((v - average).map(f32::abs).max_elem() < 1e-4, "{:?} instead of the average {:?}", v, average);
+	}
+}
+
+#[test]
+fn test_filtered_zero_footprint() {
+	let img = test_image(23, 17);
+	for i in 0..20 {
+		let uv = (0.05 * i as f32, 1.0 - 0.037 * i as f32);
+		assert_eq!(img.eval_filtered(uv, (0.0, 0.0), (0.0, 0.0)), img.eval(uv));
+	}
+}
+
+#[test]
+fn test_filtered_anisotropic_footprint() {
+	// alternating black and white texels
+	let n = 64;
+	let pixels = (0..n * n).map(|i| Vec3::thrice(((i % n + i / n) % 2) as f32)).collect();
+	let img = Image::from_pixels(n, n, pixels);
+	let texel = 1.0 / n as f32;
+	for i in 0..10 {
+		let uv = (0.1 * i as f32 + 0.013, 0.3 + 0.05 * i as f32);
+		// elongated footprints, both along the axes and diagonal, handled by EWA
+		let v = img.eval_filtered(uv, (16.0 * texel, 0.0), (0.0, 2.0 * texel));
+		assert!((v.x - 0.5).abs() < 0.05, "{} at {:?}", v.x, uv);
+		let v = img.eval_filtered(uv, (12.0 * texel, 12.0 * texel), (-texel, texel));
+		assert!((v.x - 0.5).abs() < 0.05, "{} at {:?}", v.x, uv);
+	}
+}
+
+#[test]
+fn test_checker_wide_footprint() {
+	let red = Vec3::new(1.0, 0.0, 0.0);
+	let blue = Vec3::new(0.0, 0.0, 1.0);
+	let checker = Texture::Checker { on_color: red, off_color: blue, resolution: (64.0, 64.0) };
+	for i in 0..10 {
+		let tc = TexCoords {
+			uv: (0.1 * i as f32 + 0.013, 0.3 + 0.05 * i as f32),
+			duv_dx: (0.1, 0.02),
+			duv_dy: (-0.01, 0.08),
+			..Default::default()
+		};
+		let v = checker.eval(tc);
+		assert!((v - (red + blue) * 0.5).map(f32::abs).max_elem() < 0.02, "{:?} at {:?}", v, tc.uv);
+	}
+}